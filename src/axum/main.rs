@@ -5,10 +5,13 @@ use std::future::IntoFuture;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Context;
 use axum::extract::{self, ConnectInfo};
 use axum::response::IntoResponse;
 use axum::Json;
+use base64::prelude::*;
 use foundations::cli::{Arg, ArgAction, Cli};
 use foundations::telemetry::TelemetryConfig;
 use foundations::telemetry::{
@@ -17,7 +20,7 @@ use foundations::telemetry::{
     settings::TelemetrySettings,
 };
 use foundations::BootstrapResult;
-use reqwest::header;
+use reqwest::header::{self, HeaderMap, HeaderValue};
 use tokio::net::TcpListener;
 use tokio::signal::unix;
 
@@ -52,11 +55,40 @@ async fn main() -> BootstrapResult<()> {
     let assets_path = Path::new(&env::var("ASSETS_PATH").unwrap_or("./assets".to_string()))
         .canonicalize()
         .ok();
-    let templater_state = Arc::new(State::new(templates_path, assets_path));
+    let http_settings = http_settings_from_env()?;
+    let s3_settings = s3::S3Settings {
+        force_path_style: env::var("S3_FORCE_PATH_STYLE").is_ok(),
+        ..Default::default()
+    };
+    let mut templater_state = State::new(templates_path, assets_path)
+        .with_http_settings(http_settings)
+        .with_s3_settings(s3_settings)?
+        .with_compile_limits(compile_limits_from_env()?);
+    // jobs may only keep build files if the admin chose where
+    if let Some(path) = env::var_os("ARTIFACTS_PATH") {
+        let format = match env::var("ARTIFACTS_FORMAT") {
+            Ok(format) => format.parse().context("Invalid ARTIFACTS_FORMAT")?,
+            Err(_) => Default::default(),
+        };
+        templater_state = templater_state.with_artifacts(artifacts::ArtifactsSettings {
+            path: path.into(),
+            format,
+        });
+    }
+    // WeasyPrint is often installed in a virtualenv
+    if let Ok(python) = env::var("WEASYPRINT_PYTHON") {
+        templater_state = templater_state.with_backend(backend::WeasyPrint { python });
+    }
+    let templater_state = Arc::new(templater_state);
     let may_output_file = env::var("MAY_OUTPUT_TO_FILE").is_ok();
+    // requests to S3 are signed with the server's credentials
+    let s3_allowed_buckets = env::var("S3_ALLOWED_BUCKETS")
+        .map(|buckets| buckets.split_whitespace().map(String::from).collect())
+        .unwrap_or_default();
     let server_state = ServerState {
         templater_state,
         may_output_file,
+        s3_allowed_buckets,
     };
 
     let bind_addr = "0.0.0.0:8080";
@@ -86,6 +118,56 @@ async fn main() -> BootstrapResult<()> {
     Ok(())
 }
 
+fn parse<T: std::str::FromStr>(name: &str) -> BootstrapResult<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    env::var(name)
+        .ok()
+        .map(|v| v.parse().with_context(|| format!("Invalid {}", name)))
+        .transpose()
+}
+
+/// Read the limits for compilers from `COMPILE_TIMEOUT`, `COMPILE_CPU_SECONDS` (both in seconds),
+/// `COMPILE_MEMORY` and `COMPILE_FILE_SIZE` (both in bytes).
+fn compile_limits_from_env() -> BootstrapResult<backend::CompileLimits> {
+    Ok(backend::CompileLimits {
+        timeout: parse("COMPILE_TIMEOUT")?.map(Duration::from_secs),
+        cpu_seconds: parse("COMPILE_CPU_SECONDS")?,
+        memory_bytes: parse("COMPILE_MEMORY")?,
+        file_size_bytes: parse("COMPILE_FILE_SIZE")?,
+    })
+}
+
+/// Read the limits for remote inputs from `HTTP_CONNECT_TIMEOUT`, `HTTP_READ_TIMEOUT` (both in
+/// seconds), `HTTP_RETRIES` and `MAX_INPUT_SIZE` (in bytes), the directory with secret files
+/// from `SECRETS_PATH`, and where secrets may be sent from `SECRET_URLS` (whitespace separated
+/// `NAME=URL` entries).
+fn http_settings_from_env() -> BootstrapResult<http::HttpSettings> {
+    let mut settings = http::HttpSettings::default();
+    if let Some(secs) = parse("HTTP_CONNECT_TIMEOUT")? {
+        settings.connect_timeout = Duration::from_secs(secs);
+    }
+    if let Some(secs) = parse("HTTP_READ_TIMEOUT")? {
+        settings.read_timeout = Duration::from_secs(secs);
+    }
+    if let Some(retries) = parse("HTTP_RETRIES")? {
+        settings.retries = retries;
+    }
+    if let Some(size) = parse("MAX_INPUT_SIZE")? {
+        settings.max_input_size = size;
+    }
+    settings.secrets_path = env::var_os("SECRETS_PATH").map(Into::into);
+    if let Ok(entries) = env::var("SECRET_URLS") {
+        settings.secret_urls = entries
+            .split_whitespace()
+            .map(http::parse_secret_url)
+            .collect::<anyhow::Result<_>>()
+            .context("Invalid SECRET_URLS")?;
+    }
+    Ok(settings)
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -118,18 +200,17 @@ async fn post_renderjob(
 ) -> Result<impl IntoResponse, AppError> {
     trace!("got request"; "client-ip" => format!("{}", client_addr.ip()));
 
-    if !state.may_output_file {
-        if let OutputRef::File(FileRef::File(_file)) = renderjob.output {
-            return Err(AppError::NotAllowedOutput);
-        }
+    if !state.may_run(&renderjob) {
+        return Err(AppError::NotAllowedOutput);
     }
 
     let renderer = state.templater_state.new_job(renderjob).await?;
-    match renderer.run_job().await? {
-        None => {
-            let response = RenderResponse {};
-            Ok(Json(response).into_response())
-        }
+    let job_output = renderer.run_job().await?;
+    if job_output.ensure_written().is_err() {
+        return Err(AppError::OutputFailed(job_output.report));
+    }
+    match job_output.buffer {
+        None => Ok(Json(job_output.report).into_response()),
         Some(output) => {
             let headers = [
                 (
@@ -141,10 +222,42 @@ async fn post_renderjob(
                     format!("attachment; filename=\"{}\"", output.filename),
                 ),
             ];
+            let metadata_headers = metadata_headers(&job_output.report.metadata);
+
+            Ok((metadata_headers, headers, output.buffer).into_response())
+        }
+    }
+}
+
+/// The metadata of a job, as far as it fits into headers of a response with the file.
+fn metadata_headers(metadata: &JobMetadata) -> HeaderMap {
+    let mut timings = vec![format!("render;dur={}", metadata.render_ms)];
+    if let Some(compile_ms) = metadata.compile_ms {
+        timings.push(format!("compile;dur={}", compile_ms));
+    }
+    let digest = hex::decode(&metadata.sha256).unwrap_or_default();
 
-            Ok((headers, output.buffer).into_response())
+    let mut headers = HeaderMap::new();
+    for (name, value) in [
+        ("server-timing", Some(timings.join(", "))),
+        (
+            "repr-digest",
+            Some(format!("sha-256=:{}:", BASE64_STANDARD.encode(digest))),
+        ),
+        (
+            "x-page-count",
+            metadata.pages.map(|pages| pages.to_string()),
+        ),
+        (
+            "x-compiler-warnings",
+            Some(metadata.warnings.len().to_string()),
+        ),
+    ] {
+        if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(name, value);
         }
     }
+    headers
 }
 
 #[cfg(target_os = "linux")]
@@ -3,24 +3,60 @@ use std::sync::Arc;
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
 use foundations::telemetry::log;
-use serde::{Deserialize, Serialize};
-use templater::State;
+use templater::schema::{InvalidDataError, Violation};
+use templater::{backend, FileRef, Input, JobReport, RenderJob, State};
 
 #[derive(Clone)]
 pub struct ServerState {
     pub templater_state: Arc<State>,
     pub may_output_file: bool,
+    /// The buckets that jobs may read from and write to with the server's credentials.
+    pub s3_allowed_buckets: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RenderResponse {}
+impl ServerState {
+    /// Whether the job only uses file outputs and S3 buckets the server allows.
+    pub fn may_run(&self, renderjob: &RenderJob) -> bool {
+        let inputs = renderjob.inputs.iter().filter_map(|input| match input {
+            Input::FileRef(fileref) => Some(fileref),
+            Input::Spec(spec) => match spec.source.as_ref() {
+                Input::FileRef(fileref) => Some(fileref),
+                _ => None,
+            },
+            _ => None,
+        });
+        for fileref in inputs {
+            if let FileRef::S3(location) = fileref
+                && !self.s3_allowed_buckets.contains(&location.bucket)
+            {
+                return false;
+            }
+        }
+        for output in &renderjob.output {
+            match output.target() {
+                Some(FileRef::File(_)) if !self.may_output_file => return false,
+                Some(FileRef::S3(location))
+                    if !self.s3_allowed_buckets.contains(&location.bucket) =>
+                {
+                    return false;
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+}
 
 #[derive(Debug)]
 pub enum AppError {
     AnyError(anyhow::Error),
     NotAllowedOutput,
+    InvalidData(Vec<Violation>),
+    OutputFailed(JobReport),
+    CompileTimeout(backend::CompileTimeout),
 }
 
 impl IntoResponse for AppError {
@@ -28,19 +64,68 @@ impl IntoResponse for AppError {
         match self {
             Self::AnyError(e) => {
                 log::error!("{:?}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong.")
+                (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong.").into_response()
             }
             Self::NotAllowedOutput => {
-                log::error!("Output into file not allowed.");
-                (StatusCode::BAD_REQUEST, "Invalid output.")
+                log::error!("Output into file or S3 bucket not allowed.");
+                (StatusCode::BAD_REQUEST, "Invalid output.").into_response()
+            }
+            Self::InvalidData(violations) => {
+                log::warn!("Data does not match schema."; "violations" => violations.len());
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(InvalidDataError { violations }),
+                )
+                    .into_response()
+            }
+            Self::OutputFailed(report) => {
+                log::warn!("Could not write output."; "outputs" => report.outputs.len());
+                (StatusCode::BAD_GATEWAY, Json(report)).into_response()
+            }
+            Self::CompileTimeout(e) => {
+                log::warn!("{}", e);
+                (StatusCode::GATEWAY_TIMEOUT, e.to_string()).into_response()
             }
         }
-        .into_response()
     }
 }
 
 impl From<anyhow::Error> for AppError {
     fn from(e: anyhow::Error) -> Self {
-        AppError::AnyError(e)
+        if let Some(timeout) = e.downcast_ref::<backend::CompileTimeout>() {
+            return AppError::CompileTimeout(*timeout);
+        }
+        match e.downcast::<InvalidDataError>() {
+            Ok(e) => AppError::InvalidData(e.violations),
+            Err(e) => AppError::AnyError(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_may_run() {
+        let state = ServerState {
+            templater_state: Arc::new(State::new(".", None::<&str>)),
+            may_output_file: false,
+            s3_allowed_buckets: vec!["archive".to_string()],
+        };
+        let job = |json: &str| serde_json::from_str::<RenderJob>(json).unwrap();
+
+        assert!(state.may_run(&job(
+            r#"{"template": "a.mkiv", "inputs": [{"source": "s3://archive/a.json"}],
+                "output": "s3://archive/a.pdf"}"#
+        )));
+        for disallowed in [
+            r#"{"template": "a.mkiv", "inputs": [], "output": "s3://other/a.pdf"}"#,
+            r#"{"template": "a.mkiv", "inputs": ["s3://other/a.json"]}"#,
+            r#"{"template": "a.mkiv", "inputs": [{"source": "s3://other/a.json"}]}"#,
+            r#"{"template": "a.mkiv", "inputs": [], "output": "/tmp/a.pdf"}"#,
+        ] {
+            assert!(!state.may_run(&job(disallowed)), "{}", disallowed);
+        }
     }
 }
@@ -1,5 +1,6 @@
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::Context;
 use clap::Parser;
@@ -26,11 +27,95 @@ struct Cli {
     #[structopt(long)]
     assets_path: Option<PathBuf>,
 
-    #[structopt(short, long)]
-    inputs: Vec<FileRef>,
+    /// Input file or URL, optionally prefixed with its format, e.g. `yaml:-` for YAML on stdin, or
+    /// a JSON input spec
+    #[structopt(short, long, value_parser = Input::from_str)]
+    inputs: Vec<Input>,
 
-    #[structopt(short, long, value_parser = OutputRef::from_str)]
-    output: OutputRef,
+    /// Output file, URL or `s3://bucket/key`, may be given multiple times
+    #[structopt(short, long, required = true, value_parser = OutputRef::from_str)]
+    output: Vec<OutputRef>,
+
+    /// How to combine the inputs: replace, deep_merge or append_arrays
+    #[structopt(long, default_value = "replace", value_parser = MergeStrategy::from_str)]
+    merge: MergeStrategy,
+
+    /// Timeout in seconds for connecting to remote inputs
+    #[structopt(long, default_value_t = 10)]
+    connect_timeout: u64,
+
+    /// Timeout in seconds for reading from remote inputs
+    #[structopt(long, default_value_t = 30)]
+    read_timeout: u64,
+
+    /// Retries for remote inputs on connection or server errors
+    #[structopt(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Maximum size of a remote input in bytes
+    #[structopt(long, default_value_t = 50 * 1024 * 1024)]
+    max_input_size: u64,
+
+    /// Directory with secret files that input specs may reference
+    #[structopt(long)]
+    secrets_path: Option<PathBuf>,
+
+    /// Allow the secret NAME (environment variable or secret file) to be sent to URLs starting
+    /// with URL, as NAME=URL
+    #[structopt(long = "secret-url", value_parser = http::parse_secret_url)]
+    secret_urls: Vec<(String, reqwest::Url)>,
+
+    /// Name of the produced file, rendered with the data, for outputs ending with `/`
+    #[structopt(long)]
+    filename: Option<String>,
+
+    /// Write the job report to `<file>.json` next to file outputs
+    #[structopt(long)]
+    metadata_sidecar: bool,
+
+    /// Create missing parent directories of file outputs
+    #[structopt(long)]
+    create_dirs: bool,
+
+    /// Fail instead of replacing existing files
+    #[structopt(long)]
+    no_overwrite: bool,
+
+    /// Compile with this backend instead of the one for the template's extension
+    #[structopt(long)]
+    backend: Option<String>,
+
+    /// Python interpreter that WeasyPrint is installed for, to compile HTML templates
+    #[structopt(long)]
+    weasyprint_python: Option<String>,
+
+    /// Kill the compiler if it takes longer than this many seconds
+    #[structopt(long)]
+    compile_timeout: Option<u64>,
+
+    /// CPU seconds each compiler process may use
+    #[structopt(long)]
+    compile_cpu_seconds: Option<u64>,
+
+    /// Megabytes of memory each compiler process may use
+    #[structopt(long)]
+    compile_memory_mb: Option<u64>,
+
+    /// Megabytes each file written by the compiler may have
+    #[structopt(long)]
+    compile_file_size_mb: Option<u64>,
+
+    /// Keep the rendered source and the compiler's files in this directory if compiling fails
+    #[structopt(long)]
+    keep_artifacts: Option<PathBuf>,
+
+    /// How to keep the files of a failed compile: directory or zip
+    #[structopt(long, default_value = "directory", value_parser = artifacts::ArtifactsFormat::from_str)]
+    artifacts_format: artifacts::ArtifactsFormat,
+
+    /// Address S3 objects as `<endpoint>/<bucket>/<key>`, e.g. for MinIO
+    #[structopt(long)]
+    s3_force_path_style: bool,
 
     #[structopt(short, long, action = clap::ArgAction::Count)]
     verbosity: u8,
@@ -89,13 +174,73 @@ async fn main() -> BootstrapResult<()> {
             "template" => template.as_ref(),
     );
 
-    let state = State::new(templates_path, assets_path);
-    let inputs = opts.inputs.into_iter().map(types::Input::FileRef).collect();
+    let http_settings = http::HttpSettings {
+        connect_timeout: Duration::from_secs(opts.connect_timeout),
+        read_timeout: Duration::from_secs(opts.read_timeout),
+        retries: opts.retries,
+        max_input_size: opts.max_input_size,
+        secrets_path: opts.secrets_path,
+        // the user running the cli can read any environment variable anyway
+        secret_env_prefix: String::new(),
+        secret_urls: opts.secret_urls,
+        ..Default::default()
+    };
+    let s3_settings = s3::S3Settings {
+        force_path_style: opts.s3_force_path_style,
+        ..Default::default()
+    };
+    let mut state = State::new(templates_path, assets_path)
+        .with_http_settings(http_settings)
+        .with_s3_settings(s3_settings)?
+        .with_compile_limits(backend::CompileLimits {
+            timeout: opts.compile_timeout.map(Duration::from_secs),
+            cpu_seconds: opts.compile_cpu_seconds,
+            memory_bytes: opts.compile_memory_mb.map(|mb| mb * 1024 * 1024),
+            file_size_bytes: opts.compile_file_size_mb.map(|mb| mb * 1024 * 1024),
+        });
+    if let Some(python) = opts.weasyprint_python {
+        state = state.with_backend(backend::WeasyPrint { python });
+    }
+    let keep_artifacts = opts.keep_artifacts.is_some();
+    if let Some(path) = opts.keep_artifacts {
+        state = state.with_artifacts(artifacts::ArtifactsSettings {
+            path,
+            format: opts.artifacts_format,
+        });
+    }
+
+    // the report goes to stdout, unless the file itself is written there
+    let to_stdout = opts.output.iter().any(
+        |output| matches!(output.target(), Some(FileRef::File(path)) if path.as_os_str() == "-"),
+    );
+    let file_options = FileOptions {
+        create_dirs: opts.create_dirs,
+        overwrite: !opts.no_overwrite,
+    };
+    let output = opts
+        .output
+        .into_iter()
+        .map(|output| match output {
+            OutputRef::File(FileRef::File(path)) if path.as_os_str() == "-" => {
+                OutputRef::File(FileRef::File(path))
+            }
+            OutputRef::File(target @ FileRef::File(_)) => OutputRef::Spec(OutputSpec {
+                metadata_sidecar: opts.metadata_sidecar,
+                file: file_options,
+                ..target.into()
+            }),
+            output => output,
+        })
+        .collect();
 
     let renderjob = RenderJob {
-        output: opts.output,
+        output,
         template,
-        inputs,
+        inputs: opts.inputs,
+        merge: opts.merge,
+        filename: opts.filename,
+        keep_artifacts,
+        backend: opts.backend,
     };
 
     let renderer = state
@@ -105,7 +250,14 @@ async fn main() -> BootstrapResult<()> {
 
     sandbox_syscalls(!opts.disable_sandboxing)?;
 
-    renderer.run_job().await.context("Could not run job")?;
+    let output = renderer.run_job().await.context("Could not run job")?;
+    let report = serde_json::to_string_pretty(&output.report)?;
+    if to_stdout {
+        eprintln!("{}", report);
+    } else {
+        println!("{}", report);
+    }
+    output.ensure_written()?;
     Ok(())
 }
 
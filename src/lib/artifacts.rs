@@ -0,0 +1,160 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// How the files of a failed compile are kept.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactsFormat {
+    /// Copied into a directory.
+    #[default]
+    Directory,
+    /// Bundled into a zip file.
+    Zip,
+}
+
+impl FromStr for ArtifactsFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "directory" => Ok(Self::Directory),
+            "zip" => Ok(Self::Zip),
+            _ => bail!(
+                "Unknown artifacts format {:?}, expected directory or zip",
+                s
+            ),
+        }
+    }
+}
+
+/// Where and how the files of failed compiles are kept.
+#[derive(Clone, Debug)]
+pub struct ArtifactsSettings {
+    pub path: PathBuf,
+    pub format: ArtifactsFormat,
+}
+
+impl ArtifactsSettings {
+    /// Copy all files of `dir` to `<path>/<name>/` or `<path>/<name>.zip`, and return where they
+    /// were kept.
+    pub async fn keep(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+        let settings = self.clone();
+        let dir = dir.to_path_buf();
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || settings.keep_blocking(&dir, &name)).await?
+    }
+
+    fn keep_blocking(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.path)
+            .with_context(|| format!("Could not create directory {}", self.path.display()))?;
+        let files = files_in(dir).context("Could not list build files")?;
+
+        match self.format {
+            ArtifactsFormat::Directory => {
+                let target = self.path.join(name);
+                for file in files {
+                    let target_file = target.join(&file);
+                    if let Some(parent) = target_file.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::copy(dir.join(&file), &target_file)
+                        .with_context(|| format!("Could not copy {}", file.display()))?;
+                }
+                Ok(target)
+            }
+            ArtifactsFormat::Zip => {
+                let target = self.path.join(format!("{}.zip", name));
+                let mut zip = ZipWriter::new(
+                    File::create(&target)
+                        .with_context(|| format!("Could not create {}", target.display()))?,
+                );
+                let options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+                for file in files {
+                    zip.start_file(file.to_string_lossy(), options)?;
+                    io::copy(&mut File::open(dir.join(&file))?, &mut zip)
+                        .with_context(|| format!("Could not add {}", file.display()))?;
+                }
+                zip.finish()?;
+                Ok(target)
+            }
+        }
+    }
+}
+
+/// The paths of all files below `dir`, relative to it. Symlinks, e.g. to the assets, are skipped.
+fn files_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        for entry in fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                continue;
+            } else if file_type.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_keep() -> Result<()> {
+        let build = TempDir::new().await?;
+        fs::write(
+            build.dir_path().join("invoice.mkiv"),
+            "\\starttext\\stoptext",
+        )?;
+        fs::create_dir(build.dir_path().join("cache"))?;
+        fs::write(build.dir_path().join("cache/invoice.tuc"), "return {}")?;
+        // like the assets Typst links into the job directory
+        let assets = TempDir::new().await?;
+        fs::write(assets.dir_path().join("logo.svg"), "<svg/>")?;
+        fs::create_dir(build.dir_path().join("srv"))?;
+        std::os::unix::fs::symlink(assets.dir_path(), build.dir_path().join("srv/assets"))?;
+
+        let kept = TempDir::new().await?;
+        let settings = ArtifactsSettings {
+            path: kept.dir_path().join("artifacts"),
+            format: ArtifactsFormat::Directory,
+        };
+        let path = settings.keep(build.dir_path(), "job").await?;
+        assert_eq!(path, settings.path.join("job"));
+        assert_eq!(
+            files_in(&path)?,
+            [
+                PathBuf::from("cache/invoice.tuc"),
+                PathBuf::from("invoice.mkiv")
+            ]
+        );
+
+        let settings = ArtifactsSettings {
+            format: ArtifactsFormat::Zip,
+            ..settings
+        };
+        let path = settings.keep(build.dir_path(), "job").await?;
+        let zip = zip::ZipArchive::new(File::open(path)?)?;
+        let mut names: Vec<&str> = zip.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["cache/invoice.tuc", "invoice.mkiv"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,642 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use mime_guess::{mime, Mime};
+
+use crate::metadata;
+use crate::TemplateRef;
+
+/// The files of a single compile.
+#[derive(Debug)]
+pub struct CompileJob<'a> {
+    /// The rendered template.
+    pub source: &'a Path,
+    /// Where the produced file is expected, next to the source.
+    pub output: &'a Path,
+    /// The assets that templates refer to with `__assets_path`.
+    pub assets_path: Option<&'a Path>,
+}
+
+/// Limits for a compiler and the processes it starts. Everything is unlimited by default.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompileLimits {
+    /// Wall-clock time, after which the whole process group is killed.
+    pub timeout: Option<Duration>,
+    /// CPU time of each process, in seconds.
+    pub cpu_seconds: Option<u64>,
+    /// Address space of each process, in bytes.
+    pub memory_bytes: Option<u64>,
+    /// Size of each file written, in bytes.
+    pub file_size_bytes: Option<u64>,
+}
+
+impl CompileLimits {
+    /// Apply the resource limits to the current process. Runs in the compiler process before it
+    /// executes, so it must not allocate.
+    pub fn set_rlimits(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_AS, self.memory_bytes),
+            (libc::RLIMIT_FSIZE, self.file_size_bytes),
+        ];
+        for (resource, limit) in limits {
+            if let Some(limit) = limit {
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit as libc::rlim_t,
+                    rlim_max: limit as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The compiler did not finish within `CompileLimits::timeout` and was killed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompileTimeout(pub Duration);
+
+impl fmt::Display for CompileTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Compiling took longer than {}s", self.0.as_secs_f64())
+    }
+}
+
+impl std::error::Error for CompileTimeout {}
+
+/// A compiler that turns rendered templates into documents.
+///
+/// The command runs in the job's temporary directory, which contains the rendered template and
+/// the input files.
+pub trait CompileBackend: fmt::Debug + Send + Sync {
+    /// Name to select the backend with in jobs.
+    fn name(&self) -> &'static str;
+
+    /// Template extensions that are compiled with this backend by default.
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// The program to run.
+    fn command(&self) -> &str;
+
+    /// Arguments to compile the job's rendered template.
+    fn args(&self, job: &CompileJob) -> Vec<OsString>;
+
+    /// Extension of the produced file.
+    fn output_extension(&self) -> &'static str {
+        "pdf"
+    }
+
+    /// Content type of the produced file.
+    fn mime_type(&self) -> Mime {
+        mime::APPLICATION_PDF
+    }
+
+    /// Where the compiler writes its log. Otherwise, its standard output and error are used.
+    fn log_path(&self, _job: &CompileJob) -> Option<PathBuf> {
+        None
+    }
+
+    /// Set up the job's directory before the command runs.
+    fn prepare(&self, _job: &CompileJob) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The warnings in the compiler's log.
+    fn warnings(&self, log: &str) -> Vec<String> {
+        metadata::compiler_warnings(log)
+    }
+}
+
+/// ConTeXt MKIV, the default for `.mkiv` templates.
+#[derive(Debug)]
+pub struct ConTeXt;
+
+impl CompileBackend for ConTeXt {
+    fn name(&self) -> &'static str {
+        "context"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["mkiv"]
+    }
+
+    fn command(&self) -> &str {
+        "context"
+    }
+
+    fn args(&self, job: &CompileJob) -> Vec<OsString> {
+        vec!["--batchmode".into(), job.source.into()]
+    }
+
+    fn log_path(&self, job: &CompileJob) -> Option<PathBuf> {
+        Some(job.source.with_extension("log"))
+    }
+}
+
+/// The TeX engine that latexmk runs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LatexEngine {
+    LuaLatex,
+    XeLatex,
+}
+
+/// LaTeX via latexmk, which runs the engine as often as needed to resolve references. LuaLaTeX
+/// is the default for `.tex` templates, XeLaTeX has to be selected by jobs.
+#[derive(Debug)]
+pub struct Latex(pub LatexEngine);
+
+impl CompileBackend for Latex {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            LatexEngine::LuaLatex => "lualatex",
+            LatexEngine::XeLatex => "xelatex",
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self.0 {
+            LatexEngine::LuaLatex => &["tex"],
+            LatexEngine::XeLatex => &[],
+        }
+    }
+
+    fn command(&self) -> &str {
+        "latexmk"
+    }
+
+    fn args(&self, job: &CompileJob) -> Vec<OsString> {
+        let engine = match self.0 {
+            LatexEngine::LuaLatex => "-lualatex",
+            LatexEngine::XeLatex => "-xelatex",
+        };
+        let mut outdir = OsString::from("-outdir=");
+        if let Some(dir) = job.source.parent() {
+            outdir.push(dir);
+        }
+        vec![
+            engine.into(),
+            // rc files could run arbitrary code
+            "-norc".into(),
+            "-interaction=nonstopmode".into(),
+            "-halt-on-error".into(),
+            "-file-line-error".into(),
+            outdir,
+            job.source.into(),
+        ]
+    }
+
+    fn log_path(&self, job: &CompileJob) -> Option<PathBuf> {
+        Some(job.source.with_extension("log"))
+    }
+}
+
+/// Typst, the default for `.typ` templates. Fonts are loaded from the assets as well.
+///
+/// Typst can only read files below its root, which is the job's directory. The assets are linked
+/// into it under their own path, so that `{{ __assets_path }}/logo.png` resolves to them.
+#[derive(Debug)]
+pub struct Typst;
+
+impl CompileBackend for Typst {
+    fn name(&self) -> &'static str {
+        "typst"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["typ"]
+    }
+
+    fn command(&self) -> &str {
+        "typst"
+    }
+
+    fn args(&self, job: &CompileJob) -> Vec<OsString> {
+        // the source is always in the job's directory
+        let root = job.source.parent().unwrap_or(job.source);
+        let mut args: Vec<OsString> = vec![
+            "compile".into(),
+            "--root".into(),
+            root.into(),
+            "--diagnostic-format".into(),
+            "short".into(),
+        ];
+        if let Some(assets_path) = job.assets_path {
+            args.push("--font-path".into());
+            args.push(assets_path.into());
+        }
+        args.push(job.source.into());
+        args.push(job.output.into());
+        args
+    }
+
+    fn prepare(&self, job: &CompileJob) -> io::Result<()> {
+        let (Some(root), Some(assets_path)) = (job.source.parent(), job.assets_path) else {
+            return Ok(());
+        };
+        // absolute paths are resolved against the root, relative ones against the source
+        let mut link = root.to_path_buf();
+        for component in assets_path.components() {
+            match component {
+                Component::Normal(name) => link.push(name),
+                Component::RootDir | Component::CurDir => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Cannot link assets {} for Typst", assets_path.display()),
+                    ));
+                }
+            }
+        }
+        if let Some(parent) = link.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::os::unix::fs::symlink(assets_path.canonicalize()?, link)
+    }
+}
+
+/// Runs WeasyPrint with a URL fetcher that only allows `data:` URLs and the files in the given
+/// directories, so that templates can neither make the server fetch anything over the network nor
+/// read its other files.
+const WEASYPRINT_SCRIPT: &str = r#"
+import logging, os, sys
+from urllib.parse import unquote, urlparse
+import weasyprint
+
+logging.basicConfig(format="%(levelname)s: %(message)s", level=logging.WARNING)
+
+source, output, base_url = sys.argv[1:4]
+allowed = [os.path.realpath(path) for path in sys.argv[4:]]
+
+def fetch_local(url, *args, **kwargs):
+    if url.startswith("file:"):
+        # symbolic links are resolved, so they cannot point elsewhere
+        path = os.path.realpath(unquote(urlparse(url).path))
+        if not any(os.path.commonpath([path, root]) == root for root in allowed):
+            raise ValueError(f"Only the assets and the job's files can be loaded: {url}")
+    elif not url.startswith("data:"):
+        raise ValueError(f"Network access is disabled: {url}")
+    return weasyprint.default_url_fetcher(url, *args, **kwargs)
+
+weasyprint.HTML(filename=source, base_url=base_url or None, url_fetcher=fetch_local).write_pdf(output)
+"#;
+
+/// HTML and CSS converted with WeasyPrint, the default for `.html` templates. Relative URLs are
+/// resolved against the assets, network access is disabled.
+#[derive(Debug)]
+pub struct WeasyPrint {
+    /// The Python interpreter that WeasyPrint is installed for.
+    pub python: String,
+}
+
+impl Default for WeasyPrint {
+    fn default() -> Self {
+        WeasyPrint {
+            python: "python3".to_string(),
+        }
+    }
+}
+
+impl CompileBackend for WeasyPrint {
+    fn name(&self) -> &'static str {
+        "weasyprint"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["html", "htm"]
+    }
+
+    fn command(&self) -> &str {
+        &self.python
+    }
+
+    fn args(&self, job: &CompileJob) -> Vec<OsString> {
+        // without assets, relative URLs are resolved against the job's directory
+        let base_url = job
+            .assets_path
+            .and_then(|path| reqwest::Url::from_directory_path(path).ok())
+            .map(String::from)
+            .unwrap_or_default();
+        let mut args: Vec<OsString> = vec![
+            "-c".into(),
+            WEASYPRINT_SCRIPT.into(),
+            job.source.into(),
+            job.output.into(),
+            base_url.into(),
+        ];
+        // the directories files may be loaded from
+        args.extend(job.source.parent().map(Into::into));
+        args.extend(job.assets_path.map(Into::into));
+        args
+    }
+
+    fn warnings(&self, log: &str) -> Vec<String> {
+        // failed image or stylesheet loads are logged as errors but do not fail the compile
+        metadata::warning_lines(log, |line| {
+            line.starts_with("WARNING: ") || line.starts_with("ERROR: ")
+        })
+    }
+}
+
+/// The backends a server or commandline client knows about.
+#[derive(Clone, Debug)]
+pub struct Backends(Vec<Arc<dyn CompileBackend>>);
+
+impl Default for Backends {
+    fn default() -> Self {
+        Backends(vec![
+            Arc::new(ConTeXt),
+            Arc::new(Latex(LatexEngine::LuaLatex)),
+            Arc::new(Latex(LatexEngine::XeLatex)),
+            Arc::new(Typst),
+            Arc::new(WeasyPrint::default()),
+        ])
+    }
+}
+
+impl Backends {
+    /// Add a backend. It takes precedence over earlier ones for the same extensions.
+    pub fn add(&mut self, backend: impl CompileBackend + 'static) {
+        self.0.insert(0, Arc::new(backend));
+    }
+
+    /// The backend with the given name, or the one for the template's extension. Templates
+    /// without a backend are not compiled.
+    pub fn select(
+        &self,
+        template: &TemplateRef,
+        name: Option<&str>,
+    ) -> Result<Option<Arc<dyn CompileBackend>>> {
+        let backend = match name {
+            Some(name) => match self.0.iter().find(|backend| backend.name() == name) {
+                Some(backend) => Some(backend),
+                None => bail!("Unknown compile backend {}", name),
+            },
+            None => template.extension().and_then(|extension| {
+                self.0
+                    .iter()
+                    .find(|backend| backend.extensions().contains(&extension))
+            }),
+        };
+        Ok(backend.cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Plain;
+
+    impl CompileBackend for Plain {
+        fn name(&self) -> &'static str {
+            "plain"
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            &["mkiv", "tex"]
+        }
+
+        fn command(&self) -> &str {
+            "tex"
+        }
+
+        fn args(&self, job: &CompileJob) -> Vec<OsString> {
+            vec![job.source.into()]
+        }
+    }
+
+    #[test]
+    fn test_select() -> Result<()> {
+        let template = |name: &str| TemplateRef::from(name.to_string());
+        let selected = |backends: &Backends, template: &TemplateRef, name| {
+            backends
+                .select(template, name)
+                .map(|backend| backend.map(|backend| backend.name()))
+        };
+
+        let mut backends = Backends::default();
+        assert_eq!(
+            selected(&backends, &template("invoice.mkiv"), None)?,
+            Some("context")
+        );
+        assert_eq!(
+            selected(&backends, &template("letter.tex"), None)?,
+            Some("lualatex")
+        );
+        assert_eq!(
+            selected(&backends, &template("letter.tex"), Some("xelatex"))?,
+            Some("xelatex")
+        );
+        assert_eq!(selected(&backends, &template("invoice.txt"), None)?, None);
+
+        backends.add(Plain);
+        assert_eq!(
+            selected(&backends, &template("invoice.mkiv"), None)?,
+            Some("plain")
+        );
+        assert_eq!(
+            selected(&backends, &template("invoice.mkiv"), Some("context"))?,
+            Some("context")
+        );
+        assert!(selected(&backends, &template("letter.tex"), Some("troff")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_context() {
+        let job = CompileJob {
+            source: Path::new("invoice.mkiv"),
+            output: Path::new("invoice.pdf"),
+            assets_path: None,
+        };
+        assert_eq!(ConTeXt.args(&job), ["--batchmode", "invoice.mkiv"]);
+        assert_eq!(ConTeXt.log_path(&job), Some(PathBuf::from("invoice.log")));
+        assert_eq!(ConTeXt.mime_type(), mime::APPLICATION_PDF);
+    }
+
+    #[test]
+    fn test_latex() {
+        let job = CompileJob {
+            source: Path::new("/tmp/job/letter.tex"),
+            output: Path::new("/tmp/job/letter.pdf"),
+            assets_path: None,
+        };
+        assert_eq!(
+            Latex(LatexEngine::XeLatex).args(&job),
+            [
+                "-xelatex",
+                "-norc",
+                "-interaction=nonstopmode",
+                "-halt-on-error",
+                "-file-line-error",
+                "-outdir=/tmp/job",
+                "/tmp/job/letter.tex"
+            ]
+        );
+        assert_eq!(
+            Latex(LatexEngine::LuaLatex).log_path(&job),
+            Some(PathBuf::from("/tmp/job/letter.log"))
+        );
+    }
+
+    #[test]
+    fn test_typst() -> Result<()> {
+        let template = TemplateRef::from("invoice.typ".to_string());
+        let backend = Backends::default().select(&template, None)?.unwrap();
+        assert_eq!(backend.name(), "typst");
+
+        let job = CompileJob {
+            source: Path::new("/tmp/job/invoice.typ"),
+            output: Path::new("/tmp/job/invoice.pdf"),
+            assets_path: Some(Path::new("/etc/templater/assets")),
+        };
+        assert_eq!(
+            backend.args(&job),
+            [
+                "compile",
+                "--root",
+                "/tmp/job",
+                "--diagnostic-format",
+                "short",
+                "--font-path",
+                "/etc/templater/assets",
+                "/tmp/job/invoice.typ",
+                "/tmp/job/invoice.pdf"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_typst_assets_link() -> Result<()> {
+        let dir = async_tempfile::TempDir::new().await?;
+        let assets = async_tempfile::TempDir::new().await?;
+        std::fs::write(assets.dir_path().join("logo.png"), b"png")?;
+        let source = dir.dir_path().join("invoice.typ");
+        let job = CompileJob {
+            source: &source,
+            output: &source.with_extension("pdf"),
+            assets_path: Some(assets.dir_path()),
+        };
+        Typst.prepare(&job)?;
+        let linked = dir
+            .dir_path()
+            .join(assets.dir_path().strip_prefix("/")?)
+            .join("logo.png");
+        assert_eq!(std::fs::read(linked)?, b"png");
+
+        let job = CompileJob {
+            assets_path: Some(Path::new("../assets")),
+            ..job
+        };
+        assert!(Typst.prepare(&job).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_weasyprint() -> Result<()> {
+        let template = TemplateRef::from("invoice.html".to_string());
+        let backend = Backends::default().select(&template, None)?.unwrap();
+        assert_eq!(backend.name(), "weasyprint");
+        assert_eq!(backend.mime_type(), mime::APPLICATION_PDF);
+
+        let job = CompileJob {
+            source: Path::new("/tmp/job/invoice.html"),
+            output: Path::new("/tmp/job/invoice.pdf"),
+            assets_path: Some(Path::new("/etc/templater/assets")),
+        };
+        let args = backend.args(&job);
+        assert_eq!(backend.command(), "python3");
+        assert_eq!(
+            args[2..],
+            [
+                "/tmp/job/invoice.html",
+                "/tmp/job/invoice.pdf",
+                "file:///etc/templater/assets/",
+                "/tmp/job",
+                "/etc/templater/assets"
+            ]
+        );
+
+        let log = "\
+WARNING: Ignored `colour: red` at 3:5, unknown property.
+ERROR: Failed to load image at \"https://example.com/logo.png\": Network access is disabled
+WARNING: Ignored `colour: red` at 3:5, unknown property.
+";
+        assert_eq!(
+            backend.warnings(log),
+            [
+                "WARNING: Ignored `colour: red` at 3:5, unknown property.",
+                "ERROR: Failed to load image at \"https://example.com/logo.png\": Network access is disabled",
+            ]
+        );
+        Ok(())
+    }
+
+    /// Runs the script with a stand-in for WeasyPrint that fetches the URLs listed in the source.
+    #[tokio::test]
+    async fn test_weasyprint_fetcher() -> Result<()> {
+        let dir = async_tempfile::TempDir::new().await?;
+        let assets = async_tempfile::TempDir::new().await?;
+        let stub = async_tempfile::TempDir::new().await?;
+        std::fs::write(
+            stub.dir_path().join("weasyprint.py"),
+            "def default_url_fetcher(url, *args, **kwargs):\n    return {'string': b''}\n\n\
+            class HTML:\n\
+            \x20   def __init__(self, filename, base_url, url_fetcher):\n\
+            \x20       self.urls = open(filename).read().split()\n\
+            \x20       self.fetch = url_fetcher\n\n\
+            \x20   def write_pdf(self, output):\n\
+            \x20       with open(output, 'w') as f:\n\
+            \x20           for url in self.urls:\n\
+            \x20               try:\n\
+            \x20                   self.fetch(url)\n\
+            \x20                   f.write('ok ')\n\
+            \x20               except ValueError:\n\
+            \x20                   f.write('denied ')\n",
+        )?;
+        std::fs::write(assets.dir_path().join("invoice.css"), "")?;
+        std::os::unix::fs::symlink("/etc/passwd", assets.dir_path().join("passwd"))?;
+
+        let source = dir.dir_path().join("invoice.html");
+        let url = |path: &Path| reqwest::Url::from_file_path(path).unwrap().to_string();
+        let urls = [
+            url(&assets.dir_path().join("invoice.css")),
+            url(&dir.dir_path().join("data.json")),
+            "data:text/plain,a".to_string(),
+            "file:///etc/passwd".to_string(),
+            url(&assets.dir_path().join("passwd")),
+            url(&assets.dir_path().join("../other")),
+            "https://example.com/logo.png".to_string(),
+        ];
+        std::fs::write(&source, urls.join("\n"))?;
+        let job = CompileJob {
+            source: &source,
+            output: &source.with_extension("pdf"),
+            assets_path: Some(assets.dir_path()),
+        };
+        let backend = WeasyPrint::default();
+        let Ok(status) = std::process::Command::new(backend.command())
+            .args(backend.args(&job))
+            .env("PYTHONPATH", stub.dir_path())
+            .status()
+        else {
+            // python is not installed
+            return Ok(());
+        };
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(job.output)?,
+            "ok ok ok denied denied denied denied "
+        );
+        Ok(())
+    }
+}
@@ -46,3 +46,56 @@ pub fn context_escape(input: &str) -> String {
         .replace('~', "\\lettertilde{}")
         .replace('^', "\\letterhat{}")
 }
+
+/// This will escape all special characters of LaTeX, including `\`.
+pub fn latex_escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            '{' | '}' | '#' | '$' | '%' | '&' | '_' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// This will escape all characters with a meaning in Typst markup, so the input is typeset as
+/// text.
+pub fn typst_escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        if "\\#*_$[]<>@`~=-+/".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latex_escape() {
+        assert_eq!(
+            latex_escape("50% of $10 & more_{x} #1 ~a^b \\end"),
+            "50\\% of \\$10 \\& more\\_\\{x\\} \\#1 \\textasciitilde{}a\\textasciicircum{}b \\textbackslash{}end"
+        );
+    }
+
+    #[test]
+    fn test_typst_escape() {
+        assert_eq!(
+            typst_escape("#1 *ACME* <b> $5 [x] \\u{1F600}"),
+            "\\#1 \\*ACME\\* \\<b\\> \\$5 \\[x\\] \\\\u{1F600}"
+        );
+        assert_eq!(typst_escape("Grüße"), "Grüße");
+    }
+}
@@ -0,0 +1,783 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail, ensure};
+use async_tempfile::TempFile;
+use foundations::telemetry::log::debug;
+use mime_guess::Mime;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::multipart::{Form, Part};
+use reqwest::{IntoUrl, Method, StatusCode, Url};
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+
+use crate::s3::{Upload, UploadIntegrity};
+
+/// Longer `Retry-After` delays are not waited for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Limits for fetching remote files.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpSettings {
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for the next chunk of data.
+    pub read_timeout: Duration,
+    /// How often to retry on connection errors, server errors (5xx) and rate limiting.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub retry_delay: Duration,
+    /// Maximum size of a single input in bytes.
+    pub max_input_size: u64,
+    /// Directory with secret files that inputs may reference.
+    pub secrets_path: Option<PathBuf>,
+    /// Only environment variables with this prefix may be referenced as secrets.
+    pub secret_env_prefix: String,
+    /// The URL prefixes each secret may be sent to, by environment variable or secret file
+    /// name. Other secrets are not resolved.
+    pub secret_urls: Vec<(String, Url)>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            max_input_size: 50 * 1024 * 1024,
+            secrets_path: None,
+            secret_env_prefix: "TEMPLATER_SECRET_".to_string(),
+            secret_urls: vec![],
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn client(&self) -> reqwest::Client {
+        let secret_urls = self.secret_urls.clone();
+        // reqwest keeps custom headers on redirects, so a request that may carry a secret must
+        // not be redirected to where that secret may not be sent
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            let first = attempt.previous().first().unwrap_or(attempt.url());
+            let allowed = secret_urls
+                .iter()
+                .filter(|(_, prefix)| secret_allowed(prefix, first))
+                .all(|(name, _)| {
+                    secret_urls.iter().any(|(secret, prefix)| {
+                        secret == name && secret_allowed(prefix, attempt.url())
+                    })
+                });
+            if attempt.previous().len() > 10 {
+                attempt.error("too many redirects")
+            } else if !allowed {
+                let error = format!(
+                    "Secrets may not be sent to {}",
+                    attempt.url().origin().ascii_serialization()
+                );
+                attempt.error(error)
+            } else {
+                attempt.follow()
+            }
+        });
+        reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .redirect(policy)
+            .build()
+            .expect("could not initialize http client")
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+/// A value that should not be logged, e.g. an API token.
+///
+/// It is given literally, or read from an environment variable (with the configured prefix) or a
+/// file in the secrets directory.
+#[derive(Clone, Deserialize, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum SecretValue {
+    Value(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(_) => write!(f, "Value(<redacted>)"),
+            Self::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+            Self::File { file } => f.debug_struct("File").field("file", file).finish(),
+        }
+    }
+}
+
+/// Parse `NAME=URL`, allowing the secret `NAME` to be sent to URLs starting with `URL`.
+pub fn parse_secret_url(entry: &str) -> Result<(String, Url)> {
+    let (name, prefix) = entry
+        .split_once('=')
+        .with_context(|| format!("Expected NAME=URL, got {}", entry))?;
+    let prefix = Url::parse(prefix).with_context(|| format!("Invalid URL for secret {}", name))?;
+    Ok((name.to_string(), prefix))
+}
+
+/// Whether secrets for `prefix` may be sent to `url`.
+fn secret_allowed(prefix: &Url, url: &Url) -> bool {
+    prefix.origin() == url.origin() && url.path().starts_with(prefix.path())
+}
+
+impl SecretValue {
+    /// The secret, if it may be sent to `url`. Literal values can be sent anywhere, as they come
+    /// from the job anyway.
+    pub async fn resolve(&self, settings: &HttpSettings, url: &Url) -> Result<String> {
+        let name = match self {
+            Self::Value(_) => None,
+            Self::Env { env } => Some(env.clone()),
+            Self::File { file } => Some(file.to_string_lossy().into_owned()),
+        };
+        if let Some(name) = name {
+            // otherwise, anyone who can submit jobs could send the secret to their own server
+            let allowed = settings
+                .secret_urls
+                .iter()
+                .any(|(secret, prefix)| *secret == name && secret_allowed(prefix, url));
+            ensure!(
+                allowed,
+                "Secret {} may not be sent to {}{}",
+                name,
+                url.origin().ascii_serialization(),
+                url.path()
+            );
+        }
+        match self {
+            Self::Value(value) => Ok(value.clone()),
+            Self::Env { env } => {
+                ensure!(
+                    env.starts_with(&settings.secret_env_prefix),
+                    "Secret environment variables must start with {}",
+                    settings.secret_env_prefix
+                );
+                std::env::var(env).with_context(|| format!("Cannot read secret {}", env))
+            }
+            Self::File { file } => {
+                let secrets_path = settings
+                    .secrets_path
+                    .as_ref()
+                    .context("No secrets path configured")?;
+                ensure!(
+                    Path::new(file)
+                        .components()
+                        .all(|c| matches!(c, Component::Normal(_))),
+                    "Invalid secret file {}",
+                    file.display()
+                );
+                let secret = tokio::fs::read_to_string(secrets_path.join(file))
+                    .await
+                    .with_context(|| format!("Cannot read secret {}", file.display()))?;
+                Ok(secret.trim_end().to_string())
+            }
+        }
+    }
+}
+
+/// Resolve the secrets of custom request headers. The values are marked as sensitive.
+pub async fn request_headers(
+    headers: &HashMap<String, SecretValue>,
+    bearer_token: Option<&SecretValue>,
+    settings: &HttpSettings,
+    url: &Url,
+) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::try_from(name.as_str())
+            .with_context(|| format!("Invalid header name {}", name))?;
+        let mut value = HeaderValue::try_from(value.resolve(settings, url).await?)
+            .with_context(|| format!("Invalid value for header {}", name))?;
+        value.set_sensitive(true);
+        header_map.insert(name, value);
+    }
+    if let Some(token) = bearer_token {
+        let mut value =
+            HeaderValue::try_from(format!("Bearer {}", token.resolve(settings, url).await?))
+                .context("Invalid bearer token")?;
+        value.set_sensitive(true);
+        header_map.insert(header::AUTHORIZATION, value);
+    }
+    Ok(header_map)
+}
+
+/// GET `url` and read the body, retrying on connection errors and server errors.
+pub async fn get(
+    client: &reqwest::Client,
+    settings: &HttpSettings,
+    url: &Url,
+    headers: &HeaderMap,
+) -> Result<(HeaderMap, Vec<u8>)> {
+    let req = client.get(url.clone()).headers(headers.clone()).build()?;
+    fetch(client, settings, req).await
+}
+
+/// Send `req` and read the response headers and body, which may be at most
+/// `settings.max_input_size` bytes. Retries like `execute`, and also if the connection breaks
+/// while reading the body.
+pub async fn fetch(
+    client: &reqwest::Client,
+    settings: &HttpSettings,
+    req: reqwest::Request,
+) -> Result<(HeaderMap, Vec<u8>)> {
+    let mut attempt = 0;
+    loop {
+        let attempt_req = req.try_clone().context("Request cannot be retried")?;
+        let res = execute(client, settings, attempt_req).await?;
+        let headers = res.headers().clone();
+        match read_body(res, settings.max_input_size).await {
+            Ok(body) => return Ok((headers, body)),
+            Err(e) if is_retryable_error(&e, true) && attempt < settings.retries => {
+                let delay = settings.backoff(attempt);
+                debug!("retrying request after reading the body failed";
+                    "url" => req.url().as_str(),
+                    "error" => e.to_string(),
+                    "delay-ms" => delay.as_millis(),
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Send `req`, retrying on connection errors and server errors.
+///
+/// The request must not have a streaming body, as it is cloned for every attempt.
+pub async fn execute(
+    client: &reqwest::Client,
+    settings: &HttpSettings,
+    req: reqwest::Request,
+) -> Result<reqwest::Response> {
+    let req = &req;
+    let res = with_retries(settings, req.method(), req.url(), move || async move {
+        let req = req.try_clone().context("Request cannot be retried")?;
+        Ok(client.execute(req).await?)
+    })
+    .await?;
+    Ok(res.error_for_status()?)
+}
+
+/// Call `send` until it returns a response that is not worth retrying or the retries are
+/// exhausted.
+///
+/// Connection errors, timeouts, server errors and rate limiting are retried with exponential
+/// backoff or after the delay given in `Retry-After`. Other errors are returned immediately.
+pub async fn with_retries<F, Fut>(
+    settings: &HttpSettings,
+    method: &Method,
+    url: &Url,
+    mut send: F,
+) -> Result<reqwest::Response>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<reqwest::Response>>,
+{
+    let mut attempt = 0;
+    loop {
+        let result = send().await;
+        let retryable = match &result {
+            Ok(res) => is_retryable_status(res.status()),
+            Err(e) => is_retryable_error(e, method.is_idempotent()),
+        };
+        if !retryable || attempt >= settings.retries {
+            return result;
+        }
+
+        let delay = match result.as_ref().ok().and_then(retry_after) {
+            Some(delay) if delay > MAX_RETRY_AFTER => {
+                bail!("Server asked to retry after {}s", delay.as_secs())
+            }
+            Some(delay) => delay,
+            None => settings.backoff(attempt),
+        };
+        debug!("retrying request";
+            "url" => url.as_str(),
+            "status" => result.as_ref().ok().map(|res| res.status().as_u16()),
+            "error" => result.as_ref().err().map(|e| e.to_string()),
+            "delay-ms" => delay.as_millis(),
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// A request that delivers a file to a URL other than a plain `PUT`, e.g. to WebDAV shares,
+/// document management APIs or S3 presigned POST forms.
+#[derive(Clone, Debug)]
+pub struct HttpDelivery {
+    pub method: Method,
+    pub headers: HeaderMap,
+    /// Send the file as `multipart/form-data` after these fields, in this order.
+    pub form: Option<Vec<(String, String)>>,
+}
+
+impl Default for HttpDelivery {
+    fn default() -> Self {
+        Self {
+            method: Method::PUT,
+            headers: HeaderMap::new(),
+            form: None,
+        }
+    }
+}
+
+impl HttpDelivery {
+    /// The request for one attempt to send the file, which is read from the start.
+    ///
+    /// `checksum_headers` are only sent with plain bodies: in a form they would cover the whole
+    /// form, so the store can only be verified by the response.
+    pub(crate) async fn request(
+        &self,
+        client: &reqwest::Client,
+        url: &Url,
+        reader: &TempFile,
+        mime_type: &Mime,
+        checksum_headers: HeaderMap,
+    ) -> Result<reqwest::Request> {
+        let size = reader.metadata().await?.len();
+        let body = reqwest::Body::wrap_stream(ReaderStream::new(reader.open_ro().await?));
+        let req = client
+            .request(self.method.clone(), url.clone())
+            .headers(self.headers.clone());
+        let req = match &self.form {
+            None => req
+                .header(header::CONTENT_TYPE, mime_type.as_ref())
+                .header(header::CONTENT_LENGTH, size)
+                .headers(checksum_headers)
+                .body(body),
+            Some(fields) => {
+                let filename = reader
+                    .file_path()
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("file")
+                    .to_string();
+                let file = Part::stream_with_length(body, size)
+                    .file_name(filename)
+                    .mime_str(mime_type.as_ref())?;
+                let form = fields
+                    .iter()
+                    .fold(Form::new(), |form, (name, value)| {
+                        form.text(name.clone(), value.clone())
+                    })
+                    // S3 ignores fields after the file
+                    .part("file", file);
+                req.multipart(form)
+            }
+        };
+        Ok(req.build()?)
+    }
+}
+
+/// Send the file with the method, headers and form given in `delivery`.
+pub async fn deliver_file(
+    client: &reqwest::Client,
+    settings: &HttpSettings,
+    reader: TempFile,
+    mime_type: Mime,
+    target_url: impl IntoUrl,
+    delivery: HttpDelivery,
+    integrity: UploadIntegrity,
+) -> Result<()> {
+    let upload = Upload::unsigned(
+        client,
+        settings,
+        target_url.into_url()?,
+        mime_type,
+        delivery,
+        integrity,
+    );
+    upload.put_file(reader).await
+}
+
+/// Whether the error is a connection problem that may go away when the request is sent again.
+///
+/// Other request errors, e.g. a reset connection, are only retried for idempotent requests, as
+/// the server may already have acted on the request.
+fn is_retryable_error(e: &anyhow::Error, idempotent: bool) -> bool {
+    // a connection that breaks while reading the body is reported as decoding error
+    e.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_connect()
+            || e.is_timeout()
+            || e.is_body()
+            || e.is_decode()
+            || (idempotent && e.is_request())
+    })
+}
+
+/// Whether the request may succeed when it is sent again. Other client errors are fatal.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// The delay requested by the `Retry-After` header, in seconds or as HTTP date.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let value = res.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    match value.parse() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            )
+        }
+    }
+}
+
+/// Read the response body, aborting if it exceeds `max_size` bytes.
+pub async fn read_body(mut res: reqwest::Response, max_size: u64) -> Result<Vec<u8>> {
+    if let Some(len) = res.content_length() {
+        ensure!(
+            len <= max_size,
+            "Input of {} bytes exceeds the maximum input size of {} bytes",
+            len,
+            max_size
+        );
+    }
+
+    let mut body = vec![];
+    while let Some(chunk) = res.chunk().await? {
+        ensure!(
+            (body.len() + chunk.len()) as u64 <= max_size,
+            "Input exceeds the maximum input size of {} bytes",
+            max_size
+        );
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve the given raw HTTP responses, one per connection, after reading the whole request.
+    pub(crate) async fn serve(responses: Vec<&'static str>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !is_complete(&request) {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        Url::parse(&format!("http://{}/data.json", addr)).unwrap()
+    }
+
+    /// Whether the request's headers and its body (as given by Content-Length) were read.
+    fn is_complete(request: &[u8]) -> bool {
+        let request = String::from_utf8_lossy(request);
+        let Some((head, body)) = request.split_once("\r\n\r\n") else {
+            return false;
+        };
+        let content_length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map_or(0, |(_, value)| value.trim().parse().unwrap());
+        body.len() >= content_length
+    }
+
+    fn settings() -> HttpSettings {
+        HttpSettings {
+            retry_delay: Duration::from_millis(1),
+            max_input_size: 16,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_retries() -> Result<()> {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\n{\"a\": 1}",
+        ])
+        .await;
+        let settings = settings();
+        let (_, body) = get(&settings.client(), &settings, &url, &HeaderMap::new()).await?;
+        assert_eq!(body, b"{\"a\": 1}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_retries_broken_body() -> Result<()> {
+        let url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\n{\"a\"",
+            "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\n{\"a\": 1}",
+        ])
+        .await;
+        let settings = settings();
+        let (_, body) = get(&settings.client(), &settings, &url, &HeaderMap::new()).await?;
+        assert_eq!(body, b"{\"a\": 1}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_retries_request_errors() -> Result<()> {
+        // the connection is closed without a response
+        let url = serve(vec![
+            "",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+        ])
+        .await;
+        let settings = settings();
+        let (_, body) = get(&settings.client(), &settings, &url, &HeaderMap::new()).await?;
+        assert_eq!(body, b"{}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_honours_retry_after() -> Result<()> {
+        let url = serve(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+        ])
+        .await;
+        let settings = settings();
+        let start = std::time::Instant::now();
+        get(&settings.client(), &settings, &url, &HeaderMap::new()).await?;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 3600\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let err = get(&settings.client(), &settings, &url, &HeaderMap::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("retry after 3600s"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_fails_on_client_error() -> Result<()> {
+        let url = serve(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let settings = settings();
+        assert!(
+            get(&settings.client(), &settings, &url, &HeaderMap::new())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_body_limit() -> Result<()> {
+        let url = serve(vec![
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{\"too\": \"large for the limit\"}",
+        ])
+        .await;
+        let settings = settings();
+        let err = get(&settings.client(), &settings, &url, &HeaderMap::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("maximum input size"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secrets() -> Result<()> {
+        let dir = async_tempfile::TempDir::new().await?;
+        tokio::fs::write(dir.dir_path().join("token"), "s3cr3t\n").await?;
+        let settings = HttpSettings {
+            secrets_path: Some(dir.dir_path().to_owned()),
+            secret_urls: vec![
+                parse_secret_url("token=https://api.example.com/v1/")?,
+                parse_secret_url("../token=https://api.example.com/")?,
+                parse_secret_url("HOME=https://api.example.com/")?,
+            ],
+            ..Default::default()
+        };
+        let url = Url::parse("https://api.example.com/v1/customers?id=42")?;
+
+        let secret = |s: &str| serde_json::from_str::<SecretValue>(s).unwrap();
+        assert_eq!(
+            secret(r#"{"file": "token"}"#)
+                .resolve(&settings, &url)
+                .await?,
+            "s3cr3t"
+        );
+        assert!(
+            secret(r#"{"file": "../token"}"#)
+                .resolve(&settings, &url)
+                .await
+                .is_err()
+        );
+        assert!(
+            secret(r#"{"env": "HOME"}"#)
+                .resolve(&settings, &url)
+                .await
+                .is_err()
+        );
+        for other in [
+            "https://evil.example.com/v1/",
+            "https://api.example.com.evil.com/v1/",
+            "http://api.example.com/v1/",
+            "https://api.example.com/v2/",
+        ] {
+            let err = secret(r#"{"file": "token"}"#)
+                .resolve(&settings, &Url::parse(other)?)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("may not be sent to"), "{}", other);
+        }
+        assert!(parse_secret_url("token").is_err());
+        assert_eq!(format!("{:?}", secret(r#""s3cr3t""#)), "Value(<redacted>)");
+
+        let headers = request_headers(
+            &HashMap::from([("X-Api-Key".to_string(), secret(r#""key""#))]),
+            Some(&secret(r#"{"file": "token"}"#)),
+            &settings,
+            &url,
+        )
+        .await?;
+        assert_eq!(headers["x-api-key"], "key");
+        assert_eq!(headers[header::AUTHORIZATION], "Bearer s3cr3t");
+        assert!(headers[header::AUTHORIZATION].is_sensitive());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secret_redirects() -> Result<()> {
+        let url = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /v1/other.json\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+            "HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:1/v1/data.json\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await
+        .join("/v1/data.json")?;
+        let settings = HttpSettings {
+            secret_urls: vec![parse_secret_url(&format!("token={}", url.join("/v1/")?))?],
+            ..settings()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("s3cr3t"));
+
+        let (_, body) = get(&settings.client(), &settings, &url, &headers).await?;
+        assert_eq!(body, b"{}");
+        let err = get(&settings.client(), &settings, &url, &headers)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("may not be sent to"),
+            "{:#}",
+            err
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_form() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?))?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !is_complete(&request) {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let settings = HttpSettings::default();
+        let delivery = HttpDelivery {
+            method: Method::POST,
+            form: Some(vec![
+                ("key".to_string(), "a.pdf".to_string()),
+                ("policy".to_string(), "p".to_string()),
+                ("x-amz-signature".to_string(), "s".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let tempfile = TempFile::new().await?;
+        tokio::fs::write(tempfile.file_path(), b"Test data\n").await?;
+        deliver_file(
+            &settings.client(),
+            &settings,
+            tempfile,
+            mime_guess::mime::TEXT_PLAIN,
+            url,
+            delivery,
+            UploadIntegrity::None,
+        )
+        .await?;
+
+        let request = server.await?;
+        let positions: Vec<_> = ["\"key\"", "\"policy\"", "\"x-amz-signature\"", "Test data"]
+            .iter()
+            .map(|part| request.find(part).unwrap())
+            .collect();
+        assert!(positions.is_sorted(), "{}", request);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_form_does_not_retry_request_errors() -> Result<()> {
+        // the server may have acted on the form before the connection was closed
+        let url = serve(vec![
+            "",
+            "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let settings = settings();
+        let delivery = HttpDelivery {
+            method: Method::POST,
+            form: Some(vec![]),
+            ..Default::default()
+        };
+        let err = deliver_file(
+            &settings.client(),
+            &settings,
+            TempFile::new().await?,
+            mime_guess::mime::TEXT_PLAIN,
+            url,
+            delivery,
+            UploadIntegrity::None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.downcast_ref::<reqwest::Error>().unwrap().is_request());
+        Ok(())
+    }
+}
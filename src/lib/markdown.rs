@@ -0,0 +1,198 @@
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+
+use crate::filters::context_escape;
+
+/// Convert CommonMark (with tables, footnotes and strikethrough) to ConTeXt markup.
+///
+/// Raw HTML is not interpreted, but typeset as text.
+pub fn to_context(input: &str) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+
+    let mut out = String::new();
+    let mut links = vec![];
+    let mut in_table_head = false;
+    let mut in_image = 0;
+
+    for event in Parser::new_ext(input, options) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Paragraph => {}
+                Tag::Heading { level, .. } => {
+                    out.push_str(match level {
+                        HeadingLevel::H1 => "\\section{",
+                        HeadingLevel::H2 => "\\subsection{",
+                        HeadingLevel::H3 => "\\subsubsection{",
+                        _ => "\\subsubsubsection{",
+                    });
+                }
+                Tag::BlockQuote(_) => out.push_str("\\startquotation\n"),
+                Tag::CodeBlock(_) => {
+                    ensure_newline(&mut out);
+                    // not `\starttyping`, which would end at a `\stoptyping` in the code
+                    out.push_str("\\startlines[style=mono, space=on]\n");
+                }
+                Tag::List(None) => out.push_str("\\startitemize\n"),
+                Tag::List(Some(1)) => out.push_str("\\startitemize[n]\n"),
+                Tag::List(Some(start)) => {
+                    out.push_str(&format!("\\startitemize[n][start={start}]\n"))
+                }
+                Tag::Item => out.push_str("\\item "),
+                Tag::FootnoteDefinition(label) => {
+                    out.push_str(&format!("\\footnotetext[{}]{{", footnote_label(&label)));
+                }
+                Tag::Table(_) => out.push_str("\\bTABLE\n"),
+                Tag::TableHead => {
+                    in_table_head = true;
+                    out.push_str("\\bTABLEhead\n\\bTR ");
+                }
+                Tag::TableRow => out.push_str("\\bTR "),
+                Tag::TableCell if in_table_head => out.push_str("\\bTH "),
+                Tag::TableCell => out.push_str("\\bTD "),
+                Tag::Emphasis => out.push_str("{\\em "),
+                Tag::Strong => out.push_str("{\\bf "),
+                Tag::Strikethrough => out.push_str("\\overstrike{"),
+                Tag::Link { dest_url, .. } => {
+                    links.push(dest_url.to_string());
+                    out.push_str("\\goto{");
+                }
+                Tag::Image { dest_url, .. } => {
+                    in_image += 1;
+                    out.push_str(&format!("\\externalfigure[{}]", escape(&dest_url)));
+                }
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                TagEnd::Paragraph => out.push_str("\n\n"),
+                TagEnd::Heading(_) => out.push_str("}\n\n"),
+                TagEnd::BlockQuote(_) => out.push_str("\\stopquotation\n\n"),
+                TagEnd::CodeBlock => {
+                    ensure_newline(&mut out);
+                    out.push_str("\\stoplines\n\n");
+                }
+                TagEnd::List(_) => out.push_str("\\stopitemize\n\n"),
+                TagEnd::Item => ensure_newline(&mut out),
+                TagEnd::FootnoteDefinition => {
+                    trim_end_in_place(&mut out);
+                    out.push_str("}\n\n");
+                }
+                TagEnd::Table => out.push_str("\\eTABLEbody\n\\eTABLE\n\n"),
+                TagEnd::TableHead => {
+                    in_table_head = false;
+                    out.push_str("\\eTR\n\\eTABLEhead\n\\bTABLEbody\n");
+                }
+                TagEnd::TableRow => out.push_str("\\eTR\n"),
+                TagEnd::TableCell if in_table_head => out.push_str("\\eTH "),
+                TagEnd::TableCell => out.push_str("\\eTD "),
+                TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => out.push('}'),
+                TagEnd::Link => {
+                    let url = links.pop().unwrap_or_default();
+                    out.push_str(&format!("}}[url({})]", escape(&url)));
+                }
+                TagEnd::Image => in_image -= 1,
+                _ => {}
+            },
+            // the alt text of images is not typeset
+            Event::Text(_) if in_image > 0 => {}
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => {
+                out.push_str(&escape(&text))
+            }
+            Event::Code(code) => out.push_str(&format!("{{\\tt {}}}", escape(&code))),
+            Event::FootnoteReference(label) => {
+                out.push_str(&format!("\\note[{}]", footnote_label(&label)))
+            }
+            Event::SoftBreak => out.push('\n'),
+            Event::HardBreak => out.push_str("\\crlf\n"),
+            Event::Rule => out.push_str("\\thinrule\n\n"),
+            _ => {}
+        }
+    }
+
+    trim_end_in_place(&mut out);
+    out
+}
+
+/// Like `filters::context_escape`, but escapes `\` as well.
+fn escape(input: &str) -> String {
+    input
+        .split('\\')
+        .map(context_escape)
+        .collect::<Vec<_>>()
+        .join("\\letterbackslash{}")
+}
+
+/// ConTeXt references may only contain a limited set of characters.
+fn footnote_label(label: &str) -> String {
+    let label: String = label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("md-fn-{label}")
+}
+
+fn ensure_newline(out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+fn trim_end_in_place(out: &mut String) {
+    out.truncate(out.trim_end().len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::to_context;
+
+    #[test]
+    fn test_to_context() {
+        let input = "# Dear *customer*\n\
+            \n\
+            Costs are 100 $ \\& 5 % off[^1].\n\
+            \n\
+            * one\n\
+            * [two](https://example.com/?a=1#b)\n\
+            \n\
+            | a | b |\n\
+            |---|---|\n\
+            | 1 | 2 |\n\
+            \n\
+            [^1]: Only_today.\n";
+        let expected = "\\section{Dear {\\em customer}}\n\
+            \n\
+            Costs are 100 \\letterdollar{} \\letterampersand{} 5 \\letterpercent{} off\\note[md-fn-1].\n\
+            \n\
+            \\startitemize\n\
+            \\item one\n\
+            \\item \\goto{two}[url(https://example.com/?a=1\\letterhash{}b)]\n\
+            \\stopitemize\n\
+            \n\
+            \\bTABLE\n\
+            \\bTABLEhead\n\
+            \\bTR \\bTH a\\eTH \\bTH b\\eTH \\eTR\n\
+            \\eTABLEhead\n\
+            \\bTABLEbody\n\
+            \\bTR \\bTD 1\\eTD \\bTD 2\\eTD \\eTR\n\
+            \\eTABLEbody\n\
+            \\eTABLE\n\
+            \n\
+            \\footnotetext[md-fn-1]{Only\\letterunderscore{}today.}";
+        assert_eq!(to_context(input), expected);
+    }
+
+    #[test]
+    fn test_no_tex_injection() {
+        let input = "[x](\\directlua{os.exit()}) ![](\\input{/etc/passwd})\n\
+            \n\
+            ```\n\
+            \\stoptyping \\input{/etc/passwd}\n\
+            ```\n";
+        let expected = "\\goto{x}[url(\\letterbackslash{}directlua\\{os.exit()\\})] \
+            \\externalfigure[\\letterbackslash{}input\\{/etc/passwd\\}]\n\
+            \n\
+            \\startlines[style=mono, space=on]\n\
+            \\letterbackslash{}stoptyping \\letterbackslash{}input\\{/etc/passwd\\}\n\
+            \\stoplines";
+        assert_eq!(to_context(input), expected);
+    }
+}
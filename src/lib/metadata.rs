@@ -0,0 +1,197 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use mime_guess::{mime, Mime};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// At most this many compiler warnings are reported for a job.
+const MAX_WARNINGS: usize = 100;
+
+/// Facts about the produced file and how long it took to produce it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct JobMetadata {
+    pub filename: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file.
+    pub sha256: String,
+    /// Number of pages, for PDFs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pages: Option<usize>,
+    pub render_ms: u64,
+    /// Time spent in the compiler, if the template is compiled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compile_ms: Option<u64>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+impl JobMetadata {
+    /// Hash the file while reading it in chunks, so that it is never held in memory at once.
+    pub async fn of_file(filename: String, mime_type: &Mime, path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path).await?;
+        let mut sha256 = Sha256::new();
+        let mut size = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            sha256.update(&buf[..n]);
+            size += n as u64;
+        }
+
+        let pages = if mime_type.essence_str() == mime::APPLICATION_PDF.essence_str() {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || pdf_page_count(&path))
+                .await
+                .map_err(io::Error::other)?
+        } else {
+            None
+        };
+        Ok(JobMetadata {
+            filename,
+            mime_type: mime_type.essence_str().to_string(),
+            size,
+            sha256: hex::encode(sha256.finalize()),
+            pages,
+            ..Default::default()
+        })
+    }
+}
+
+/// Milliseconds, as reported in the metadata.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// The number of pages of a PDF, or `None` if it cannot be parsed.
+///
+/// Only the cross-reference table and the page tree are parsed, not the whole document.
+pub fn pdf_page_count(path: &Path) -> Option<usize> {
+    lopdf::Document::load_metadata(path)
+        .map(|metadata| metadata.page_count as usize)
+        .ok()
+}
+
+/// Lines of the compiler output that report problems with the document, such as overfull
+/// boxes or missing characters.
+pub fn compiler_warnings(output: &str) -> Vec<String> {
+    warning_lines(output, |line| {
+        line.starts_with("Overfull")
+            || line.starts_with("Underfull")
+            || line.starts_with("Missing character")
+            || line.contains(" is missing")
+            || line.to_lowercase().contains("warning")
+    })
+}
+
+/// The distinct trimmed lines of the compiler output that `is_warning` accepts, at most
+/// `MAX_WARNINGS` of them.
+pub fn warning_lines(output: &str, is_warning: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut warnings: Vec<String> = vec![];
+    for line in output.lines().map(str::trim) {
+        if is_warning(line) && !warnings.iter().any(|w| w == line) {
+            warnings.push(line.to_string());
+            if warnings.len() == MAX_WARNINGS {
+                break;
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A minimal PDF with `pages` empty pages.
+    fn pdf(pages: usize) -> Vec<u8> {
+        let kids: Vec<String> = (0..pages).map(|i| format!("{} 0 R", i + 3)).collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids.join(" "),
+                pages
+            ),
+        ];
+        for _ in 0..pages {
+            objects.push("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] >>".to_string());
+        }
+
+        let mut out = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).into_bytes());
+        }
+        let xref = out.len();
+        out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+        for offset in offsets {
+            out.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+        }
+        out.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .into_bytes(),
+        );
+        out
+    }
+
+    #[tokio::test]
+    async fn test_metadata() -> io::Result<()> {
+        let dir = async_tempfile::TempDir::new().await.unwrap();
+        let path = dir.dir_path().join("a.pdf");
+        let contents = pdf(3);
+        tokio::fs::write(&path, &contents).await?;
+        let metadata =
+            JobMetadata::of_file("a.pdf".to_string(), &mime::APPLICATION_PDF, &path).await?;
+        assert_eq!(metadata.pages, Some(3));
+        assert_eq!(metadata.size, contents.len() as u64);
+        assert_eq!(metadata.sha256.len(), 64);
+
+        tokio::fs::write(&path, b"Test data\n").await?;
+        let metadata = JobMetadata::of_file("a.txt".to_string(), &mime::TEXT_PLAIN, &path).await?;
+        assert_eq!(metadata.pages, None);
+        assert_eq!(
+            metadata.sha256,
+            "c0f5efbef0fe98aa90619444250b1a5eb23158d6686f0b190838f3d544ec85b9"
+        );
+
+        assert_eq!(pdf_page_count(&path), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compiler_warnings() {
+        let output = "\
+system          > ConTeXt  ver: 2023.03.10 14:44 LMTX  fmt: 2023.5.9  int: english/english
+fonts           > checking > char U+2603 in font 'DejaVuSans' with id 1 is missing
+Overfull \\hbox (12.0pt too wide) in paragraph at lines 10--12
+Overfull \\hbox (12.0pt too wide) in paragraph at lines 10--12
+references      > warning: unknown reference 'intro'
+mkiv lua stats  > runtime: 0.412 seconds, 1 processed page
+Missing character: There is no ☃ (U+2603) in font [lmroman10-regular]:+tlig;!
+LaTeX Warning: Reference `intro' on page 1 undefined on input line 12.
+";
+        assert_eq!(
+            compiler_warnings(output),
+            [
+                "fonts           > checking > char U+2603 in font 'DejaVuSans' with id 1 is missing",
+                "Overfull \\hbox (12.0pt too wide) in paragraph at lines 10--12",
+                "references      > warning: unknown reference 'intro'",
+                "Missing character: There is no ☃ (U+2603) in font [lmroman10-regular]:+tlig;!",
+                "LaTeX Warning: Reference `intro' on page 1 undefined on input line 12.",
+            ]
+        );
+    }
+}
@@ -1,28 +1,48 @@
+pub mod artifacts;
+pub mod backend;
 pub mod filters;
+pub mod http;
+pub mod markdown;
+pub mod metadata;
 pub mod s3;
+pub mod schema;
 pub mod types;
 
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::sync::Arc;
 use std::sync::OnceLock;
+use std::time::Instant;
 
 use anyhow::Context;
 use foundations::security::common_syscall_allow_lists::*;
-use foundations::telemetry::log::debug;
+use foundations::telemetry::log::{debug, warn};
+use futures::{StreamExt, TryStreamExt};
 use tokio::fs;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
 
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
 use async_tempfile::{Ownership, TempDir, TempFile};
 
+pub use metadata::JobMetadata;
 pub use types::*;
 
+/// How many inputs of a job are fetched at the same time.
+const MAX_CONCURRENT_INPUTS: usize = 4;
+
 #[derive(Debug)]
 pub struct State {
     reqwest_client: OnceLock<reqwest::Client>,
+    http_settings: http::HttpSettings,
+    s3_client: Arc<s3::S3Client>,
     jinja_env: Arc<minijinja::Environment<'static>>,
+    templates_path: PathBuf,
+    assets_path: Option<PathBuf>,
+    artifacts: Option<artifacts::ArtifactsSettings>,
+    backends: backend::Backends,
+    compile_limits: backend::CompileLimits,
 }
 
 impl State {
@@ -31,8 +51,9 @@ impl State {
 
         jinja_env.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
 
-        if let Some(assets_path) = assets_path {
-            jinja_env.add_global("__assets_path", assets_path.as_ref().to_str().unwrap());
+        let assets_path = assets_path.map(|path| path.as_ref().to_path_buf());
+        if let Some(assets_path) = &assets_path {
+            jinja_env.add_global("__assets_path", assets_path.to_str().unwrap());
         }
 
         jinja_env.add_global(
@@ -42,7 +63,9 @@ impl State {
         jinja_env.add_filter("currency_format", filters::currency_format);
         jinja_env.add_filter("split", filters::split);
         jinja_env.add_filter("context_escape", filters::context_escape);
-        jinja_env.set_loader(minijinja::path_loader(templates_path));
+        jinja_env.add_filter("latex_escape", filters::latex_escape);
+        jinja_env.add_filter("typst_escape", filters::typst_escape);
+        jinja_env.set_loader(minijinja::path_loader(&templates_path));
 
         let jinja_env = Arc::new(jinja_env);
         let reqwest_client = OnceLock::new();
@@ -50,75 +73,357 @@ impl State {
         State {
             jinja_env,
             reqwest_client,
+            http_settings: Default::default(),
+            s3_client: Default::default(),
+            templates_path: templates_path.as_ref().to_path_buf(),
+            assets_path,
+            artifacts: None,
+            backends: Default::default(),
+            compile_limits: Default::default(),
         }
     }
 
+    /// Use the given timeouts, retries and size limits for remote files.
+    pub fn with_http_settings(mut self, http_settings: http::HttpSettings) -> Self {
+        self.http_settings = http_settings;
+        self
+    }
+
+    /// Use the given settings for `s3://` files.
+    ///
+    /// Fails if the part size is below the 5 MiB S3 requires.
+    pub fn with_s3_settings(mut self, s3_settings: s3::S3Settings) -> Result<Self> {
+        self.s3_client = Arc::new(s3::S3Client::new(s3_settings)?);
+        Ok(self)
+    }
+
+    /// Allow jobs to keep the files of failed compiles in the given place.
+    pub fn with_artifacts(mut self, artifacts: artifacts::ArtifactsSettings) -> Self {
+        self.artifacts = Some(artifacts);
+        self
+    }
+
+    /// Register an additional compile backend.
+    pub fn with_backend(mut self, backend: impl backend::CompileBackend + 'static) -> Self {
+        self.backends.add(backend);
+        self
+    }
+
+    /// Limit the time and resources compilers may use.
+    pub fn with_compile_limits(mut self, compile_limits: backend::CompileLimits) -> Self {
+        self.compile_limits = compile_limits;
+        self
+    }
+
+    /// Set up a job and validate its data against the template's schema.
+    ///
+    /// Schema violations are reported as `schema::InvalidDataError`.
     pub async fn new_job(&self, job: RenderJob) -> Result<Renderer> {
-        Renderer::setup(
+        let artifacts = match (job.keep_artifacts, &self.artifacts) {
+            (false, _) => None,
+            (true, Some(artifacts)) => Some(artifacts.clone()),
+            (true, None) => bail!("Keeping build artifacts is not enabled"),
+        };
+        let backend = self
+            .backends
+            .select(&job.template, job.backend.as_deref())?;
+        let settings = RenderSettings {
+            artifacts,
+            backend,
+            compile_limits: self.compile_limits,
+            assets_path: self.assets_path.clone(),
+        };
+        let renderer = Renderer::setup(
             self.reqwest_client
-                .get_or_init(reqwest::Client::new)
+                .get_or_init(|| self.http_settings.client())
                 .clone(),
+            self.http_settings.clone(),
+            self.s3_client.clone(),
             self.jinja_env.clone(),
+            settings,
             job,
         )
-        .await
+        .await?;
+        schema::validate(&self.templates_path, &renderer.template, &renderer.data).await?;
+        Ok(renderer)
     }
 }
 
+/// How a job is compiled, as chosen by the server and the job.
+#[derive(Clone, Default)]
+pub struct RenderSettings {
+    /// Where to keep the files of failed compiles, if the job asks for it.
+    pub artifacts: Option<artifacts::ArtifactsSettings>,
+    /// The compiler for the template, or `None` if the rendered template is the output.
+    pub backend: Option<Arc<dyn backend::CompileBackend>>,
+    pub compile_limits: backend::CompileLimits,
+    pub assets_path: Option<PathBuf>,
+}
+
 pub struct Renderer {
     dir: TempDir,
     reqwest_client: reqwest::Client,
+    http_settings: http::HttpSettings,
+    s3_client: Arc<s3::S3Client>,
     jinja_env: Arc<minijinja::Environment<'static>>,
     template: TemplateRef,
-    output: OutputRef,
+    output: Vec<OutputRef>,
+    filename: Option<String>,
+    artifacts: Option<artifacts::ArtifactsSettings>,
+    backend: Option<Arc<dyn backend::CompileBackend>>,
+    compile_limits: backend::CompileLimits,
+    assets_path: Option<PathBuf>,
     data: HashMap<String, minijinja::Value>,
 }
 
 impl Renderer {
     pub async fn setup(
         reqwest_client: reqwest::Client,
+        http_settings: http::HttpSettings,
+        s3_client: Arc<s3::S3Client>,
         jinja_env: Arc<minijinja::Environment<'static>>,
+        settings: RenderSettings,
         job: RenderJob,
     ) -> Result<Self> {
+        ensure!(!job.output.is_empty(), "No output given");
+        ensure!(
+            job.output
+                .iter()
+                .filter(|output| **output == OutputRef::Buffer)
+                .count()
+                <= 1,
+            "Only one output can be returned as buffer"
+        );
+        for output in &job.output {
+            output.validate()?;
+        }
+
         let dir = TempDir::new().await?;
 
+        // inputs are fetched concurrently, but merged in order
         let mut data: HashMap<String, minijinja::Value> = Default::default();
-        for input in job.inputs.into_iter() {
-            data.extend(input.read_into_env(&reqwest_client).await?.into_iter());
+        {
+            let mut inputs = futures::stream::iter(job.inputs)
+                .map(|input| input.read_into_env(&reqwest_client, &http_settings, &s3_client))
+                .buffered(MAX_CONCURRENT_INPUTS);
+            while let Some(input_data) = inputs.try_next().await? {
+                job.merge.merge(&mut data, input_data);
+            }
         }
 
         Ok(Self {
             dir,
             reqwest_client,
+            http_settings,
+            s3_client,
             jinja_env,
             data,
             template: job.template,
             output: job.output,
+            filename: job.filename,
+            artifacts: settings.artifacts,
+            backend: settings.backend,
+            compile_limits: settings.compile_limits,
+            assets_path: settings.assets_path,
         })
     }
 
-    pub async fn run_job(&self) -> Result<Option<OutputBuffer>> {
-        let mut output_file = self
+    pub async fn run_job(&self) -> Result<JobOutput> {
+        let started = Instant::now();
+        let (mut output_file, template_filename) = self
             .write_template()
             .await
             .context("Could not create template")?;
+        let render_ms = metadata::millis(started.elapsed());
+
+        let mut compile_ms = None;
+        let mut warnings = vec![];
+        if let Some(backend) = &self.backend {
+            let started = Instant::now();
+            let compiled = match self.compile(backend.as_ref(), &output_file).await {
+                Err(e) => match self.keep_artifacts().await {
+                    Some(path) => Err(e.context(format!("Build files kept in {}", path.display()))),
+                    None => Err(e),
+                },
+                compiled => compiled,
+            };
+            (output_file, warnings) = compiled.context("Could not compile file")?;
+            compile_ms = Some(metadata::millis(started.elapsed()));
+        }
+        let mime_type = match &self.backend {
+            Some(backend) => backend.mime_type(),
+            None => self.template.mime_type(),
+        };
 
-        if self.template.should_compile() {
-            output_file = self
-                .compile_pdf(&output_file)
+        // unwrap is safe, because it's no directory
+        let default_filename = output_file
+            .file_path()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let filename = match (&self.filename, template_filename) {
+            (Some(pattern), _) => output_filename(
+                &self
+                    .jinja_env
+                    .render_str(pattern, &self.data)
+                    .context("Could not render output filename")?,
+                default_filename,
+            )?,
+            (None, Some(filename)) => output_filename(&filename, default_filename)?,
+            (None, None) => default_filename.to_string(),
+        };
+        let output = self
+            .output
+            .iter()
+            .map(|output| output.with_filename(&filename))
+            .collect::<Vec<_>>();
+
+        let metadata = JobMetadata {
+            render_ms,
+            compile_ms,
+            warnings,
+            ..JobMetadata::of_file(filename.clone(), &mime_type, output_file.file_path())
                 .await
-                .context("Could not compile pdf")?;
+                .context("Could not read from file")?
+        };
+
+        // all other destinations are written to at the same time
+        let mut outputs = futures::future::join_all(output.iter().filter_map(|output| {
+            let target = output.target()?;
+            let output_file = &output_file;
+            let mime_type = mime_type.clone();
+            Some(async move {
+                let started = Instant::now();
+                let result = self.write_output(output_file, mime_type, output).await;
+                if let Err(e) = &result {
+                    debug!("could not write output"; "target" => %target, "error" => ?e);
+                }
+                OutputResult {
+                    target: target.to_string(),
+                    error: result.err().map(|e| format!("{:#}", e)),
+                    duration_ms: metadata::millis(started.elapsed()),
+                }
+            })
+        }))
+        .await;
+
+        // sidecars are written last, so that they contain the results of all destinations
+        let report = JobReport {
+            metadata,
+            outputs: outputs.clone(),
+        };
+        let with_targets = output.iter().filter(|output| output.target().is_some());
+        for (output, result) in with_targets.zip(outputs.iter_mut()) {
+            let Some(sidecar_path) = output.sidecar_path() else {
+                continue;
+            };
+            if let Err(e) = write_sidecar(&sidecar_path, &report, output.file_options()).await {
+                debug!("could not write sidecar"; "path" => %sidecar_path.display(), "error" => ?e);
+                result.error.get_or_insert(format!("{:#}", e));
+            }
+        }
+
+        // the file is only held in memory if it is returned
+        let buffer = if output.contains(&OutputRef::Buffer) {
+            let mut contents = vec![];
+            output_file
+                .open_ro()
+                .await
+                .context("Could not open file")?
+                .read_to_end(&mut contents)
+                .await
+                .context("Could not read from file")?;
+            Some(OutputBuffer {
+                buffer: contents,
+                filename,
+                mime_type,
+            })
+        } else {
+            None
+        };
+
+        Ok(JobOutput {
+            buffer,
+            report: JobReport { outputs, ..report },
+        })
+    }
+
+    /// Keep the files of the job for debugging, if the job asks for it. Failures are only logged,
+    /// so that they do not hide the error of the job.
+    async fn keep_artifacts(&self) -> Option<PathBuf> {
+        let artifacts = self.artifacts.as_ref()?;
+        // the temporary directory's name is unique
+        let name = format!(
+            "{}-{}",
+            self.template.as_ref().replace('/', "_"),
+            self.dir.dir_path().file_name()?.to_string_lossy()
+        );
+        match artifacts.keep(self.dir.dir_path(), &name).await {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("could not keep build files"; "error" => ?e);
+                None
+            }
         }
-        let mime_type = self.template.mime_type();
-
-        match &self.output {
-            OutputRef::File(FileRef::Url(url)) => {
-                s3::upload_file(&self.reqwest_client, output_file, mime_type, url.clone())
-                    .await
-                    .context("Could not upload file")?;
-                Ok(None)
+    }
+
+    /// Write the file to the target of `output`.
+    async fn write_output(
+        &self,
+        output_file: &TempFile,
+        mime_type: mime_guess::Mime,
+        output: &OutputRef,
+    ) -> Result<()> {
+        let (integrity, delivery) = match output {
+            OutputRef::Spec(spec) => (
+                spec.integrity,
+                spec.http_delivery(&self.http_settings).await?,
+            ),
+            _ => (None, None),
+        };
+        let mut output_file = output_file.open_ro().await.context("Could not open file")?;
+
+        match (output.target(), delivery) {
+            (Some(FileRef::Url(url)), Some(delivery)) => {
+                http::deliver_file(
+                    &self.reqwest_client,
+                    &self.http_settings,
+                    output_file,
+                    mime_type,
+                    url.clone(),
+                    delivery,
+                    integrity.unwrap_or(s3::UploadIntegrity::None),
+                )
+                .await
+                .context("Could not deliver file")?;
             }
-            OutputRef::File(FileRef::File(filename)) => {
+            (Some(FileRef::Url(url)), None) => {
+                s3::upload_file(
+                    &self.reqwest_client,
+                    &self.http_settings,
+                    output_file,
+                    mime_type,
+                    url.clone(),
+                    integrity.unwrap_or_default(),
+                )
+                .await
+                .context("Could not upload file")?;
+            }
+            (Some(FileRef::S3(location)), _) => {
+                s3::upload_object(
+                    &self.reqwest_client,
+                    &self.http_settings,
+                    &self.s3_client,
+                    output_file,
+                    mime_type,
+                    location,
+                    integrity.unwrap_or_default(),
+                )
+                .await
+                .context("Could not upload file")?;
+            }
+            (Some(FileRef::File(filename)), _) => {
                 if filename.as_os_str() == "-" {
                     let mut buf: [u8; 64] = [0; 64];
                     let mut stdout = io::stdout();
@@ -136,49 +441,38 @@ impl Renderer {
                             .context("Could not write to stdout")?;
                     }
                 } else {
-                    let _ = fs::copy(output_file.file_path(), filename)
+                    let options = output.file_options();
+                    let tempfile = tempfile_next_to(filename, options).await?;
+                    fs::copy(output_file.file_path(), tempfile.file_path())
                         .await
                         .context("Could not copy file")?;
+                    persist(tempfile, filename, options).await?;
                 }
-                Ok(None)
-            }
-            OutputRef::Buffer => {
-                // unwrap is safe, because it's no directory
-                let filename = output_file
-                    .file_path()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string();
-
-                let mut buffer = vec![];
-                output_file
-                    .read_to_end(&mut buffer)
-                    .await
-                    .context("Could not read from file")?;
-
-                Ok(Some(OutputBuffer {
-                    buffer,
-                    filename,
-                    mime_type,
-                }))
             }
+            (None, _) => {}
         }
+        Ok(())
     }
 
-    pub async fn write_template(&self) -> Result<TempFile> {
+    /// Render the template into a file, and return it with the `output_filename` the template
+    /// may set.
+    pub async fn write_template(&self) -> Result<(TempFile, Option<String>)> {
         let templated_file =
             TempFile::new_with_name_in(self.template.as_ref(), self.dir.dir_path().to_owned())
                 .await
                 .context("Could not create template file")?;
 
-        let rendered = self
+        let captured = self
             .jinja_env
             .get_template(self.template.as_ref())
             .context("Could not get template")?
-            .render(&self.data)
+            .render_captured(&self.data)
             .context("Could not render template")?;
+        let filename = captured
+            .state()
+            .lookup("output_filename")
+            .and_then(|filename| filename.as_str().map(str::to_string));
+        let rendered = captured.into_output();
         let mut f = templated_file
             .open_rw()
             .await
@@ -186,43 +480,447 @@ impl Renderer {
         f.write_all(rendered.as_bytes())
             .await
             .context("Could not write rendered template")?;
-        Ok(templated_file)
+        // tokio writes in the background, so the compiler could otherwise read a partial file
+        f.flush()
+            .await
+            .context("Could not write rendered template")?;
+        Ok((templated_file, filename))
     }
 
-    pub async fn compile_pdf(&self, file: &TempFile) -> Result<TempFile> {
-        // create TempFile but with .pdf extension
+    /// Compile the file with the backend, and return the produced file with the warnings of the
+    /// compiler.
+    pub async fn compile(
+        &self,
+        backend: &dyn backend::CompileBackend,
+        file: &TempFile,
+    ) -> Result<(TempFile, Vec<String>)> {
         let path = file.file_path();
-        let output_file_name: &Path = path.file_stem().unwrap().as_ref();
-        let output_file_name = output_file_name.with_extension("pdf");
-        let output_file_path = path.with_file_name(output_file_name);
-        debug!("trying to compile"; "template-file" => path.to_str(), "output-file" => output_file_path.to_str());
-
-        let command = "context";
-        let context_proc = Command::new(command)
-            .arg("--batchmode")
-            .arg(path)
+        let output_file_path = path.with_extension(backend.output_extension());
+        let job = backend::CompileJob {
+            source: path,
+            output: &output_file_path,
+            assets_path: self.assets_path.as_deref(),
+        };
+        debug!("trying to compile"; "backend" => backend.name(), "template-file" => path.to_str(), "output-file" => output_file_path.to_str());
+
+        backend
+            .prepare(&job)
+            .with_context(|| format!("Could not prepare {}", backend.name()))?;
+        let mut command = Command::new(backend.command());
+        command
+            .args(backend.args(&job))
             .current_dir(&self.dir)
-            .output()
-            .await
-            .context("Could not spawn command")?;
-        let status = context_proc.status;
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // a group of its own, so that a timeout also kills e.g. the engines latexmk runs
+            .process_group(0)
+            .kill_on_drop(true);
+        let limits = self.compile_limits;
+        // SAFETY: setting the limits neither allocates nor takes locks
+        unsafe {
+            command.pre_exec(move || limits.set_rlimits());
+        }
+        let child = command
+            .spawn()
+            .with_context(|| format!("Could not spawn {}", backend.command()))?;
+        let _group = child.id().map(|pid| ProcessGroup(pid as libc::pid_t));
+        let compile_proc = match self.compile_limits.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, child.wait_with_output()).await {
+                Ok(output) => output?,
+                Err(_) => {
+                    warn!("killed compiler after timeout"; "backend" => backend.name(), "timeout-s" => timeout.as_secs());
+                    return Err(backend::CompileTimeout(timeout).into());
+                }
+            },
+            None => child.wait_with_output().await?,
+        };
+        let status = compile_proc.status;
 
-        debug!("ran pdf compilation"; "status" => status.code());
+        debug!("ran compilation"; "status" => status.code());
         debug!(
             "stdout: {:?}",
-            String::from_utf8_lossy(&context_proc.stdout)
+            String::from_utf8_lossy(&compile_proc.stdout)
         );
         debug!(
             "stderr: {:?}",
-            String::from_utf8_lossy(&context_proc.stderr)
+            String::from_utf8_lossy(&compile_proc.stderr)
         );
 
-        ensure!(status.success(), "Could not compile file");
+        if !status.success() {
+            // kept with the build files, if the job asks for them
+            let _ = fs::write(path.with_extension("stdout"), &compile_proc.stdout).await;
+            let _ = fs::write(path.with_extension("stderr"), &compile_proc.stderr).await;
+            bail!("Could not compile file");
+        }
 
+        let log = match backend.log_path(&job) {
+            Some(log_path) => fs::read(log_path).await.unwrap_or_default(),
+            None => [compile_proc.stdout, compile_proc.stderr].concat(),
+        };
+        let warnings = backend.warnings(&String::from_utf8_lossy(&log));
         let output_file = TempFile::from_existing(output_file_path, Ownership::Owned)
             .await
             .context("Could not open existing file as tempfile")?;
-        Ok(output_file)
+        Ok((output_file, warnings))
+    }
+}
+
+/// The process group of a compiler, which is killed when this is dropped. So nothing the compiler
+/// started in the background outlives the compile, whether it finished, timed out or the job was
+/// cancelled.
+struct ProcessGroup(libc::pid_t);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        // the id cannot be reused while any process of the group is left
+        // SAFETY: kill does not touch memory
+        unsafe { libc::kill(-self.0, libc::SIGKILL) };
+    }
+}
+
+/// Check a rendered output file name, and give it the extension of the produced file if it has
+/// none.
+fn output_filename(rendered: &str, default: &str) -> Result<String> {
+    let filename = rendered.trim();
+    ensure!(
+        !filename.is_empty()
+            && filename != "."
+            && filename != ".."
+            && !filename.contains(['/', '\\', '"'])
+            && !filename.contains(char::is_control),
+        "Invalid output filename {:?}",
+        filename
+    );
+    match Path::new(default).extension() {
+        Some(extension) if Path::new(filename).extension().is_none() => {
+            Ok(format!("{}.{}", filename, extension.to_string_lossy()))
+        }
+        _ => Ok(filename.to_string()),
+    }
+}
+
+/// Write the report as JSON to `path`.
+async fn write_sidecar(path: &Path, report: &JobReport, options: FileOptions) -> Result<()> {
+    let json = serde_json::to_vec_pretty(report)?;
+    let tempfile = tempfile_next_to(path, options).await?;
+    fs::write(tempfile.file_path(), json)
+        .await
+        .with_context(|| format!("Could not write metadata to {}", path.display()))?;
+    persist(tempfile, path, options).await
+}
+
+/// Create a temporary file in the directory of `path`, so it can be renamed to `path` once it
+/// is written completely.
+async fn tempfile_next_to(path: &Path, options: FileOptions) -> Result<TempFile> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if options.create_dirs {
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    }
+    TempFile::new_in(dir)
+        .await
+        .with_context(|| format!("Could not create temporary file for {}", path.display()))
+}
+
+/// Move the written temporary file to `path` atomically, so readers never see a partial file.
+async fn persist(tempfile: TempFile, path: &Path, options: FileOptions) -> Result<()> {
+    fs::File::open(tempfile.file_path())
+        .await?
+        .sync_all()
+        .await
+        .context("Could not sync file")?;
+    if options.overwrite {
+        fs::rename(tempfile.file_path(), path)
+            .await
+            .with_context(|| format!("Could not move file to {}", path.display()))?;
+    } else {
+        // unlike rename, linking fails if the file exists; the tempfile is removed on drop
+        match fs::hard_link(tempfile.file_path(), path).await {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                bail!("{} already exists", path.display())
+            }
+            result => {
+                result.with_context(|| format!("Could not move file to {}", path.display()))?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_filename() {
+        assert_eq!(
+            output_filename("invoice-42.pdf", "invoice.pdf").unwrap(),
+            "invoice-42.pdf"
+        );
+        assert_eq!(
+            output_filename(" invoice-42\n", "invoice.pdf").unwrap(),
+            "invoice-42.pdf"
+        );
+        for invalid in [
+            "",
+            "..",
+            "../invoice.pdf",
+            "a\\b.pdf",
+            "a\"b.pdf",
+            "a\rb.pdf",
+        ] {
+            assert!(
+                output_filename(invalid, "invoice.pdf").is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inputs_merged_in_order() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::net::TcpListener;
+
+        // `/<n>` answers `{"value": n}`, later inputs faster than earlier ones
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (running_, max_running_) = (running.clone(), max_running.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (running, max_running) = (running_.clone(), max_running_.clone());
+                tokio::spawn(async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    let mut request = vec![];
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let n: u64 = request.split(['/', ' ']).nth(2).unwrap().parse().unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis((6 - n) * 50)).await;
+                    let body = format!("{{\"value\": {}}}", n);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        let job = RenderJob {
+            template: TemplateRef::from("invoice.txt".to_string()),
+            output: vec![OutputRef::Buffer],
+            filename: None,
+            keep_artifacts: false,
+            backend: None,
+            inputs: (0..6)
+                .map(|n| format!("http://{}/{}", addr, n).parse())
+                .collect::<Result<_>>()?,
+            merge: Default::default(),
+        };
+        let renderer = Renderer::setup(
+            reqwest::Client::new(),
+            Default::default(),
+            Default::default(),
+            Arc::new(minijinja::Environment::new()),
+            Default::default(),
+            job,
+        )
+        .await?;
+        assert_eq!(renderer.data["value"], minijinja::Value::from(5));
+        assert_eq!(max_running.load(Ordering::SeqCst), MAX_CONCURRENT_INPUTS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_persist() -> Result<()> {
+        let dir = TempDir::new().await?;
+        let path = dir.dir_path().join("out/invoice.pdf");
+
+        assert!(
+            tempfile_next_to(&path, FileOptions::default())
+                .await
+                .is_err()
+        );
+
+        let options = FileOptions {
+            create_dirs: true,
+            overwrite: false,
+        };
+        let tempfile = tempfile_next_to(&path, options).await?;
+        fs::write(tempfile.file_path(), b"first").await?;
+        persist(tempfile, &path, options).await?;
+        assert_eq!(fs::read(&path).await?, b"first");
+
+        let tempfile = tempfile_next_to(&path, options).await?;
+        fs::write(tempfile.file_path(), b"second").await?;
+        let err = persist(tempfile, &path, options).await.unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        assert_eq!(fs::read(&path).await?, b"first");
+
+        let options = FileOptions::default();
+        let tempfile = tempfile_next_to(&path, options).await?;
+        fs::write(tempfile.file_path(), b"second").await?;
+        persist(tempfile, &path, options).await?;
+        assert_eq!(fs::read(&path).await?, b"second");
+
+        // no temporary files are left behind
+        let mut entries = fs::read_dir(path.parent().unwrap()).await?;
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, ["invoice.pdf"]);
+        Ok(())
+    }
+
+    /// Runs the rendered template as a shell script.
+    #[derive(Debug)]
+    struct Shell;
+
+    impl backend::CompileBackend for Shell {
+        fn name(&self) -> &'static str {
+            "shell"
+        }
+
+        fn extensions(&self) -> &'static [&'static str] {
+            &["sh"]
+        }
+
+        fn command(&self) -> &str {
+            "sh"
+        }
+
+        fn args(&self, job: &backend::CompileJob) -> Vec<std::ffi::OsString> {
+            vec![job.source.into()]
+        }
+    }
+
+    /// Wait until the process in `pid_file` is killed. It may be left as zombie, as it is no
+    /// child of ours.
+    async fn assert_killed(pid_file: &Path) {
+        let pid = fs::read_to_string(pid_file).await.unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let mut killed = false;
+        for _ in 0..50 {
+            killed = match fs::read_to_string(&stat).await {
+                Ok(stat) => stat.rsplit(") ").next().is_some_and(|s| s.starts_with('Z')),
+                Err(_) => true,
+            };
+            if killed {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(killed, "background process {} still runs", pid.trim());
+    }
+
+    #[tokio::test]
+    async fn test_compile_limits() -> Result<()> {
+        let templates = TempDir::new().await?;
+        // the job's directory is removed with the job, so the pids are written next to the
+        // templates
+        let pid_file = |name: &str| templates.dir_path().join(format!("{}.pid", name));
+        fs::write(
+            templates.dir_path().join("slow.sh"),
+            format!(
+                "sleep 10 & echo $! > {}; sleep 10",
+                pid_file("slow").display()
+            ),
+        )
+        .await?;
+        fs::write(
+            templates.dir_path().join("background.sh"),
+            format!(
+                "sleep 10 > /dev/null 2>&1 & echo $! > {}; : > background.pdf",
+                pid_file("background").display()
+            ),
+        )
+        .await?;
+        fs::write(
+            templates.dir_path().join("large.sh"),
+            "head -c 4096 /dev/zero > large.pdf",
+        )
+        .await?;
+        let state = State::new(templates.dir_path(), None::<&Path>)
+            .with_backend(Shell)
+            .with_compile_limits(backend::CompileLimits {
+                timeout: Some(std::time::Duration::from_millis(500)),
+                file_size_bytes: Some(1024),
+                ..Default::default()
+            });
+        let job = |template: &str| RenderJob {
+            template: TemplateRef::from(template.to_string()),
+            output: vec![OutputRef::Buffer],
+            filename: None,
+            keep_artifacts: false,
+            backend: None,
+            inputs: vec![],
+            merge: Default::default(),
+        };
+
+        let started = Instant::now();
+        let err = state
+            .new_job(job("slow.sh"))
+            .await?
+            .run_job()
+            .await
+            .unwrap_err();
+        assert!(started.elapsed().as_secs() < 5);
+        assert!(err.downcast_ref::<backend::CompileTimeout>().is_some());
+        assert_killed(&pid_file("slow")).await;
+
+        // also if the compiler exits normally
+        state.new_job(job("background.sh")).await?.run_job().await?;
+        assert_killed(&pid_file("background")).await;
+
+        // or if the job is cancelled
+        fs::remove_file(pid_file("slow")).await?;
+        let renderer = state.new_job(job("slow.sh")).await?;
+        let started = async {
+            while !fs::read_to_string(pid_file("slow"))
+                .await
+                .is_ok_and(|pid| pid.ends_with('\n'))
+            {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::select! {
+            _ = renderer.run_job() => panic!("the job should run until it is cancelled"),
+            _ = started => {}
+        }
+        assert_killed(&pid_file("slow")).await;
+
+        // writing beyond the file size limit kills the compiler
+        let err = state
+            .new_job(job("large.sh"))
+            .await?
+            .run_job()
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<backend::CompileTimeout>().is_none());
+        assert!(
+            err.chain()
+                .any(|e| e.to_string() == "Could not compile file"),
+            "{:#}",
+            err
+        );
+        Ok(())
     }
 }
 
@@ -242,6 +940,7 @@ foundations::security::allow_list! {
         execve,
         fchmod,
         fcntl,
+        fsync,
         getcwd,
         getdents64,
         getegid,
@@ -253,6 +952,11 @@ foundations::security::allow_list! {
         getresuid,
         gettimeofday,
         getuid,
+        kill,
+        link,
+        linkat,
+        mkdir,
+        mkdirat,
         openat,
         pidfd_open,
         pipe2,
@@ -263,6 +967,9 @@ foundations::security::allow_list! {
         rt_sigaction,
         rt_sigreturn,
         set_tid_address,
+        setpgid,
+        symlink,
+        symlinkat,
         sysinfo,
         uname,
         unlink,
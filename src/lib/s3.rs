@@ -1,73 +1,718 @@
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::SystemTime;
 
-use anyhow::{anyhow, ensure};
+use anyhow::{Context, Result, anyhow, bail, ensure};
 use async_tempfile::TempFile;
+use aws_config::{BehaviorVersion, SdkConfig};
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sigv4::http_request::{
+    PayloadChecksumKind, PercentEncodingMode, SignableBody, SignableRequest, SigningSettings,
+    UriPathNormalizationMode, sign,
+};
+use aws_sigv4::sign::v4;
+use base64::prelude::{BASE64_STANDARD, Engine as _};
 use foundations::telemetry::log::{debug, trace};
 use md5::{Digest, Md5};
 use mime_guess::Mime;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
 use reqwest::{
-    IntoUrl,
-    header::{self, ETAG},
+    IntoUrl, Method, Url,
+    header::{self, ETAG, HeaderMap, HeaderName, HeaderValue},
 };
-use tokio_util::io::{InspectReader, ReaderStream};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::OnceCell;
+
+use crate::http::{self, HttpDelivery, HttpSettings};
+
+/// S3 does not accept more parts in a multipart upload.
+const MAX_PARTS: u64 = 10_000;
+
+/// S3 rejects smaller parts, except for the last one.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Characters of object keys that are sent as is, as required by SigV4.
+const KEY_CHARS: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// An object in S3, given as `s3://bucket/key`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl TryFrom<&Url> for S3Location {
+    type Error = anyhow::Error;
+
+    fn try_from(url: &Url) -> Result<Self> {
+        ensure!(url.scheme() == "s3", "Not an S3 URL: {}", url);
+        let bucket = url
+            .host_str()
+            .filter(|bucket| !bucket.is_empty())
+            .with_context(|| format!("Missing bucket in {}", url))?;
+        let key = percent_decode_str(url.path().trim_start_matches('/'))
+            .decode_utf8()
+            .with_context(|| format!("Invalid key in {}", url))?;
+        ensure!(!key.is_empty(), "Missing key in {}", url);
+        Ok(Self {
+            bucket: bucket.to_string(),
+            key: key.into_owned(),
+        })
+    }
+}
+
+impl fmt::Display for S3Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Settings for `s3://` files.
+///
+/// Credentials, region and endpoint are read from the usual AWS environment variables
+/// (`AWS_ACCESS_KEY_ID`, `AWS_REGION`, `AWS_ENDPOINT_URL`, ...) and config files.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct S3Settings {
+    /// Address objects as `<endpoint>/<bucket>/<key>` instead of `<bucket>.<endpoint>/<key>`,
+    /// as needed for MinIO.
+    pub force_path_style: bool,
+    /// Outputs larger than this are uploaded in parts of this size (at least 5 MiB).
+    pub part_size: u64,
+}
+
+impl Default for S3Settings {
+    fn default() -> Self {
+        Self {
+            force_path_style: false,
+            part_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Signs requests to S3 with SigV4.
+///
+/// The AWS configuration is only loaded once it is needed.
+#[derive(Debug, Default)]
+pub struct S3Client {
+    settings: S3Settings,
+    config: OnceCell<SdkConfig>,
+}
+
+impl S3Client {
+    pub fn new(settings: S3Settings) -> Result<Self> {
+        ensure!(
+            settings.part_size >= MIN_PART_SIZE,
+            "S3 part size must be at least {MIN_PART_SIZE} bytes"
+        );
+        Ok(Self {
+            settings,
+            config: OnceCell::new(),
+        })
+    }
+
+    async fn config(&self) -> &SdkConfig {
+        self.config
+            .get_or_init(|| aws_config::load_defaults(BehaviorVersion::latest()))
+            .await
+    }
+
+    async fn region(&self) -> Result<&str> {
+        Ok(self
+            .config()
+            .await
+            .region()
+            .context("No AWS region configured")?
+            .as_ref())
+    }
+
+    /// The HTTP URL of an object.
+    pub async fn object_url(&self, location: &S3Location) -> Result<Url> {
+        let mut endpoint = match self.config().await.endpoint_url() {
+            Some(endpoint) => Url::parse(endpoint).context("Invalid AWS endpoint URL")?,
+            None => Url::parse(&format!(
+                "https://s3.{}.amazonaws.com",
+                self.region().await?
+            ))?,
+        };
+        let key = utf8_percent_encode(&location.key, KEY_CHARS);
+        let url = if self.settings.force_path_style {
+            format!(
+                "{}/{}/{}",
+                endpoint.as_str().trim_end_matches('/'),
+                location.bucket,
+                key
+            )
+        } else {
+            let host = endpoint.host_str().context("Invalid AWS endpoint URL")?;
+            endpoint.set_host(Some(&format!("{}.{}", location.bucket, host)))?;
+            format!("{}/{}", endpoint.as_str().trim_end_matches('/'), key)
+        };
+        Url::parse(&url).with_context(|| format!("Invalid S3 location {}", location))
+    }
+
+    /// Add the SigV4 authorization headers to `req`.
+    async fn sign(&self, req: &mut reqwest::Request, body: SignableBody<'_>) -> Result<()> {
+        let config = self.config().await;
+        let credentials = config
+            .credentials_provider()
+            .context("No AWS credentials configured")?
+            .provide_credentials()
+            .await
+            .context("Cannot load AWS credentials")?;
+        let identity = credentials.into();
+
+        let mut settings = SigningSettings::default();
+        settings.percent_encoding_mode = PercentEncodingMode::Single;
+        settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+        settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(self.region().await?)
+            .name("s3")
+            .time(SystemTime::now())
+            .settings(settings)
+            .build()?
+            .into();
+
+        let headers = req
+            .headers()
+            .iter()
+            .map(|(name, value)| Ok((name.as_str(), value.to_str()?)))
+            .collect::<Result<Vec<_>>>()?;
+        let signable = SignableRequest::new(
+            req.method().as_str(),
+            req.url().as_str(),
+            headers.into_iter(),
+            body,
+        )?;
+        let (instructions, _signature) = sign(signable, &params)?.into_parts();
+
+        let (signing_headers, _params) = instructions.into_parts();
+        for signing_header in signing_headers {
+            let mut value = HeaderValue::try_from(signing_header.value())?;
+            value.set_sensitive(signing_header.sensitive());
+            req.headers_mut()
+                .insert(HeaderName::from_static(signing_header.name()), value);
+        }
+        Ok(())
+    }
+
+    /// GET an object and read it, retrying like other remote inputs.
+    pub async fn get_object(
+        &self,
+        client: &reqwest::Client,
+        settings: &HttpSettings,
+        location: &S3Location,
+    ) -> Result<(Url, HeaderMap, Vec<u8>)> {
+        let url = self.object_url(location).await?;
+        let mut req = client.get(url.clone()).build()?;
+        self.sign(&mut req, SignableBody::empty()).await?;
+        let (headers, body) = http::fetch(client, settings, req).await?;
+        Ok((url, headers, body))
+    }
+}
+
+/// How to verify that an upload arrived intact.
+///
+/// Only S3 without SSE-KMS returns the MD5 of an object as ETag, other stores and encrypted
+/// buckets need one of the other strategies.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadIntegrity {
+    /// The ETag must be the MD5 of the file, or the composite MD5 of a multipart upload.
+    #[default]
+    Etag,
+    /// Send `Content-MD5` and let the store verify it.
+    ContentMd5,
+    /// Send `x-amz-checksum-sha256` and compare it to the checksum the store returns.
+    Sha256,
+    /// Only require an ETag, whatever it is.
+    OpaqueEtag,
+    /// Do not verify the upload.
+    None,
+}
+
+const CHECKSUM_SHA256: HeaderName = HeaderName::from_static("x-amz-checksum-sha256");
+
+/// MD5 and SHA-256 of a file or part.
+struct Checksums {
+    md5: Vec<u8>,
+    sha256: Vec<u8>,
+}
+
+impl Checksums {
+    fn of(bytes: &[u8]) -> Self {
+        Self {
+            md5: Md5::digest(bytes).to_vec(),
+            sha256: Sha256::digest(bytes).to_vec(),
+        }
+    }
+
+    /// Hash the whole file and rewind it.
+    async fn of_file(reader: &mut TempFile) -> Result<Self> {
+        let mut md5 = Md5::new();
+        let mut sha256 = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            md5.update(&buf[..n]);
+            sha256.update(&buf[..n]);
+        }
+        reader.rewind().await?;
+        Ok(Self {
+            md5: md5.finalize().to_vec(),
+            sha256: sha256.finalize().to_vec(),
+        })
+    }
+}
+
+impl UploadIntegrity {
+    /// Headers that let the store verify the body itself.
+    ///
+    /// The default `Etag` adds none, so requests stay as they were before it existed.
+    fn request_headers(self, checksums: &Checksums) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        match self {
+            Self::ContentMd5 => {
+                headers.insert(
+                    "Content-MD5",
+                    BASE64_STANDARD.encode(&checksums.md5).try_into()?,
+                );
+            }
+            Self::Sha256 => {
+                headers.insert(
+                    CHECKSUM_SHA256,
+                    BASE64_STANDARD.encode(&checksums.sha256).try_into()?,
+                );
+            }
+            Self::Etag | Self::OpaqueEtag | Self::None => {}
+        }
+        Ok(headers)
+    }
+
+    /// Check the response to the upload of a file or part.
+    fn verify(self, res: &reqwest::Response, checksums: &Checksums) -> Result<()> {
+        match self {
+            Self::Etag => {
+                let etag = etag(res)?;
+                let md5sum = hex::encode(&checksums.md5);
+                trace!("uploaded"; "url" => res.url().as_str(), "md5sum" => &md5sum, "etag" => &etag);
+                ensure!(md5sum == etag, "ETAG not like md5sum!");
+            }
+            Self::Sha256 => {
+                let checksum = res
+                    .headers()
+                    .get(CHECKSUM_SHA256)
+                    .context("Checksum header not found")?
+                    .to_str()?;
+                ensure!(
+                    checksum == BASE64_STANDARD.encode(&checksums.sha256),
+                    "Checksum not like sha256sum!"
+                );
+            }
+            Self::OpaqueEtag => {
+                etag(res)?;
+            }
+            Self::ContentMd5 | Self::None => {}
+        }
+        Ok(())
+    }
+}
 
 pub async fn upload_file(
     client: &reqwest::Client,
+    http_settings: &HttpSettings,
     reader: TempFile,
     mime_type: Mime,
     target_url: impl IntoUrl,
-) -> anyhow::Result<()> {
-    let target_url: reqwest::Url = target_url.into_url()?;
-    let target_url_string = target_url.to_string();
-    debug!("uploading file"; "url" => &target_url_string);
-
-    let hasher = Md5::new();
-    let hasher_rc = Arc::new(Mutex::new(hasher));
-
-    let hasher_rc2 = hasher_rc.clone();
-    let hashing_reader = InspectReader::new(reader, move |bytes| {
-        hasher_rc2.lock().unwrap().update(bytes)
-    });
-    let stream = ReaderStream::new(hashing_reader);
-    let body = reqwest::Body::wrap_stream(stream);
-    let req = client
-        .put(target_url)
-        .header(header::CONTENT_TYPE, mime_type.as_ref())
-        .body(body)
-        .build()?;
-    let etag = client
-        .execute(req)
-        .await?
+    integrity: UploadIntegrity,
+) -> Result<()> {
+    let upload = Upload::unsigned(
+        client,
+        http_settings,
+        target_url.into_url()?,
+        mime_type,
+        Default::default(),
+        integrity,
+    );
+    upload.put_file(reader).await
+}
+
+/// Upload to `s3://bucket/key`, signing the request with the configured credentials.
+///
+/// Files larger than the configured part size are uploaded in parts.
+pub async fn upload_object(
+    client: &reqwest::Client,
+    http_settings: &HttpSettings,
+    s3_client: &S3Client,
+    reader: TempFile,
+    mime_type: Mime,
+    location: &S3Location,
+    integrity: UploadIntegrity,
+) -> Result<()> {
+    let upload = Upload {
+        client,
+        http_settings,
+        signer: Some(s3_client),
+        target_url: s3_client.object_url(location).await?,
+        mime_type,
+        delivery: Default::default(),
+        integrity,
+    };
+    let size = reader.metadata().await?.len();
+    if size > s3_client.settings.part_size {
+        upload.multipart(s3_client, reader, size).await
+    } else {
+        upload.put_file(reader).await
+    }
+}
+
+/// An upload of a single file.
+pub(crate) struct Upload<'a> {
+    client: &'a reqwest::Client,
+    http_settings: &'a HttpSettings,
+    signer: Option<&'a S3Client>,
+    target_url: Url,
+    mime_type: Mime,
+    delivery: HttpDelivery,
+    integrity: UploadIntegrity,
+}
+
+impl<'a> Upload<'a> {
+    /// An upload to a URL that needs no signing.
+    pub(crate) fn unsigned(
+        client: &'a reqwest::Client,
+        http_settings: &'a HttpSettings,
+        target_url: Url,
+        mime_type: Mime,
+        delivery: HttpDelivery,
+        integrity: UploadIntegrity,
+    ) -> Self {
+        Self {
+            client,
+            http_settings,
+            signer: None,
+            target_url,
+            mime_type,
+            delivery,
+            integrity,
+        }
+    }
+
+    /// Send the `method` request built by `build`, signed if needed, retrying as configured.
+    async fn send<F, Fut>(
+        &self,
+        method: &Method,
+        body: SignableBody<'_>,
+        build: F,
+    ) -> Result<reqwest::Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<reqwest::Request>>,
+    {
+        let (body, build) = (&body, &build);
+        let url = &self.target_url;
+        http::with_retries(self.http_settings, method, url, move || async move {
+            let mut req = build().await?;
+            if let Some(s3_client) = self.signer {
+                s3_client.sign(&mut req, body.clone()).await?;
+            }
+            Ok(self.client.execute(req).await?)
+        })
+        .await
+    }
+
+    pub(crate) async fn put_file(&self, mut reader: TempFile) -> Result<()> {
+        debug!("uploading file";
+            "url" => self.target_url.as_str(),
+            "method" => self.delivery.method.as_str(),
+            "integrity" => ?self.integrity,
+        );
+
+        let checksums = Checksums::of_file(&mut reader).await?;
+        let (reader, checksums) = (&reader, &checksums);
+        let res = self
+            .send(
+                &self.delivery.method,
+                SignableBody::UnsignedPayload,
+                move || async move {
+                    self.delivery
+                        .request(
+                            self.client,
+                            &self.target_url,
+                            reader,
+                            &self.mime_type,
+                            self.integrity.request_headers(checksums)?,
+                        )
+                        .await
+                },
+            )
+            .await?;
+        if !res.status().is_success() {
+            bail!("Upload failed with status {}", res.status());
+        }
+        self.integrity.verify(&res, checksums)
+    }
+
+    /// Upload in parts, checking every part and the whole object as given by `integrity`.
+    ///
+    /// The upload is aborted on errors, so no orphaned parts are kept (and billed).
+    async fn multipart(&self, s3_client: &S3Client, mut reader: TempFile, size: u64) -> Result<()> {
+        let part_size = s3_client.settings.part_size.max(size.div_ceil(MAX_PARTS));
+        debug!("uploading file in parts";
+            "url" => self.target_url.as_str(),
+            "size" => size,
+            "part-size" => part_size,
+            "integrity" => ?self.integrity,
+        );
+
+        let res = self
+            .send(&Method::POST, SignableBody::empty(), move || async move {
+                let mut url = self.target_url.clone();
+                url.set_query(Some("uploads"));
+                let mut req = self
+                    .client
+                    .post(url)
+                    .header(header::CONTENT_TYPE, self.mime_type.as_ref());
+                if self.integrity == UploadIntegrity::Sha256 {
+                    req = req.header("x-amz-checksum-algorithm", "SHA256");
+                }
+                Ok(req.build()?)
+            })
+            .await?;
+        if !res.status().is_success() {
+            bail!("Could not start upload, status {}", res.status());
+        }
+        let upload_id = xml_element(&res.text().await?, "UploadId")
+            .context("Upload id not found")?
+            .to_string();
+
+        let result = self.parts(&mut reader, &upload_id, part_size).await;
+        if result.is_err() {
+            let mut url = self.target_url.clone();
+            url.query_pairs_mut().append_pair("uploadId", &upload_id);
+            let aborted = match self.client.delete(url).build() {
+                Ok(mut req) => match s3_client.sign(&mut req, SignableBody::empty()).await {
+                    Ok(()) => self.client.execute(req).await.map_err(Into::into),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = aborted {
+                debug!("could not abort upload"; "url" => self.target_url.as_str(), "error" => %e);
+            }
+        }
+        result
+    }
+
+    async fn parts(&self, reader: &mut TempFile, upload_id: &str, part_size: u64) -> Result<()> {
+        let integrity = self.integrity;
+        let mut parts = String::new();
+        let mut part_md5s = vec![];
+        let mut part_sha256s = vec![];
+        let mut part_count = 0;
+        loop {
+            let mut part = vec![];
+            (&mut *reader)
+                .take(part_size)
+                .read_to_end(&mut part)
+                .await?;
+            if part.is_empty() {
+                break;
+            }
+            part_count += 1;
+            let checksums = Checksums::of(&part);
+
+            let mut url = self.target_url.clone();
+            url.query_pairs_mut()
+                .append_pair("partNumber", &part_count.to_string())
+                .append_pair("uploadId", upload_id);
+            let (url, part, checksums) = (&url, &part, &checksums);
+            let res = self
+                .send(
+                    &Method::PUT,
+                    SignableBody::UnsignedPayload,
+                    move || async move {
+                        Ok(self
+                            .client
+                            .put(url.clone())
+                            .headers(integrity.request_headers(checksums)?)
+                            .body(part.clone())
+                            .build()?)
+                    },
+                )
+                .await?;
+            if !res.status().is_success() {
+                bail!(
+                    "Upload of part {} failed with status {}",
+                    part_count,
+                    res.status()
+                );
+            }
+            integrity
+                .verify(&res, checksums)
+                .with_context(|| format!("Upload of part {} is corrupt", part_count))?;
+
+            let checksum = match integrity {
+                UploadIntegrity::Sha256 => format!(
+                    "<ChecksumSHA256>{}</ChecksumSHA256>",
+                    BASE64_STANDARD.encode(&checksums.sha256)
+                ),
+                _ => String::new(),
+            };
+            parts.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>\"{}\"</ETag>{}</Part>",
+                part_count,
+                etag(&res)?,
+                checksum
+            ));
+            part_md5s.extend_from_slice(&checksums.md5);
+            part_sha256s.extend_from_slice(&checksums.sha256);
+        }
+
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        let mut url = self.target_url.clone();
+        url.query_pairs_mut().append_pair("uploadId", upload_id);
+        let attempts = AtomicU32::new(0);
+        let (url, body, attempts) = (&url, &body, &attempts);
+        let res = self
+            .send(
+                &Method::POST,
+                SignableBody::Bytes(body.as_bytes()),
+                move || async move {
+                    attempts.fetch_add(1, Ordering::Relaxed);
+                    Ok(self
+                        .client
+                        .post(url.clone())
+                        .header(header::CONTENT_TYPE, "application/xml")
+                        .body(body.clone())
+                        .build()?)
+                },
+            )
+            .await?;
+        let status = res.status();
+        // errors may also be reported after a 200 OK
+        let text = res.text().await?;
+        let (etag, checksum) = if status.is_success() && xml_element(&text, "Error").is_none() {
+            (
+                xml_element(&text, "ETag").map(|etag| etag.replace("&quot;", "").replace('"', "")),
+                xml_element(&text, "ChecksumSHA256").map(str::to_string),
+            )
+        } else if attempts.load(Ordering::Relaxed) > 1
+            && xml_element(&text, "Code") == Some("NoSuchUpload")
+        {
+            // an earlier attempt completed the upload, but its response was lost
+            self.head().await.with_context(|| {
+                format!("Could not complete upload, status {}: {}", status, text)
+            })?
+        } else {
+            bail!("Could not complete upload, status {}: {}", status, text);
+        };
+
+        match integrity {
+            UploadIntegrity::Etag => {
+                let etag = etag.context("ETAG not found")?;
+                let expected = composite_etag(&part_md5s, part_count);
+                trace!("uploaded file"; "url" => self.target_url.as_str(), "expected" => &expected, "etag" => &etag);
+                ensure!(etag == expected, "ETAG not like composite md5sum!");
+            }
+            UploadIntegrity::Sha256 => {
+                let checksum = checksum.context("Checksum not found")?;
+                let expected = format!(
+                    "{}-{}",
+                    BASE64_STANDARD.encode(Sha256::digest(&part_sha256s)),
+                    part_count
+                );
+                ensure!(
+                    checksum == expected,
+                    "Checksum not like composite sha256sum!"
+                );
+            }
+            UploadIntegrity::OpaqueEtag => {
+                etag.context("ETAG not found")?;
+            }
+            UploadIntegrity::ContentMd5 | UploadIntegrity::None => {}
+        }
+        Ok(())
+    }
+
+    /// The ETag and SHA-256 checksum of the uploaded object.
+    async fn head(&self) -> Result<(Option<String>, Option<String>)> {
+        let res = self
+            .send(&Method::HEAD, SignableBody::empty(), move || async move {
+                Ok(self
+                    .client
+                    .head(self.target_url.clone())
+                    .header("x-amz-checksum-mode", "ENABLED")
+                    .build()?)
+            })
+            .await?;
+        ensure!(
+            res.status().is_success(),
+            "Uploaded object not found, status {}",
+            res.status()
+        );
+        let checksum = match res.headers().get(CHECKSUM_SHA256) {
+            Some(checksum) => Some(checksum.to_str()?.to_string()),
+            None => None,
+        };
+        Ok((Some(etag(&res)?), checksum))
+    }
+}
+
+/// The ETag S3 computes for multipart uploads: the MD5 of the concatenated MD5s of all parts,
+/// followed by the number of parts.
+fn composite_etag(part_md5s: &[u8], part_count: u32) -> String {
+    format!("{}-{}", hex::encode(Md5::digest(part_md5s)), part_count)
+}
+
+/// The ETag header without quotes.
+fn etag(res: &reqwest::Response) -> Result<String> {
+    let etag = res
         .headers()
         .get(ETAG)
         .ok_or(anyhow!("ETAG header not found"))?
-        .to_str()?
-        .to_owned();
+        .to_str()?;
 
     // strip leading and trailing "
-    let etag = etag.strip_prefix('"').unwrap_or(&etag);
+    let etag = etag.strip_prefix('"').unwrap_or(etag);
     let etag = etag.strip_suffix('"').unwrap_or(etag);
+    Ok(etag.to_string())
+}
 
-    let md5sum = Arc::try_unwrap(hasher_rc)
-        .map_err(|_| anyhow!("Lock still has multiple owners!."))?
-        .into_inner()?
-        .finalize();
-    let md5sum = hex::encode(md5sum);
-
-    trace!("uploaded file"; "url" => target_url_string, "md5sum" => &md5sum, "etag" => etag);
-
-    ensure!(md5sum == etag, "ETAG not like md5sum!");
-    Ok(())
+/// The content of the first `name` element. S3's responses are simple enough to not need a full
+/// XML parser.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
 }
 
 #[cfg(test)]
 mod test {
     use anyhow::Result;
     use async_tempfile::TempFile;
+    use aws_config::{Region, SdkConfig};
     use aws_sdk_s3::presigning::PresigningConfig;
     use mime_guess::mime;
     use std::{env, time::Duration};
     use tokio::io::AsyncWriteExt;
+    use tokio::sync::OnceCell;
+
+    use super::{Checksums, S3Client, S3Location, S3Settings, UploadIntegrity};
 
     async fn s3_config() -> aws_sdk_s3::Config {
         let endpoint_url = env::var("AWS_ENDPOINT_URL").unwrap();
@@ -114,6 +759,7 @@ mod test {
     }
 
     #[tokio::test]
+    #[allow(clippy::let_unit_value)]
     async fn test_presigned_put() -> Result<()> {
         let bucket = env::var("S3_BUCKET").unwrap();
         let key = "test-key";
@@ -129,11 +775,255 @@ mod test {
         }
         let presigned_url = get_presigned_put_url(&bucket, key, presigned_ttl).await?;
         let mime_type = mime::TEXT_PLAIN;
-        let _ = super::upload_file(&reqwest_client, tempfile, mime_type, presigned_url).await?;
+        let _ = super::upload_file(
+            &reqwest_client,
+            &Default::default(),
+            tempfile,
+            mime_type,
+            presigned_url,
+            Default::default(),
+        )
+        .await?;
 
         // cleanup
         let _ = remove_bucket_key(&bucket, key).await;
 
         Ok(())
     }
+
+    fn s3_client(force_path_style: bool, endpoint_url: Option<&str>) -> S3Client {
+        let mut config = SdkConfig::builder().region(Region::new("eu-central-1"));
+        if let Some(endpoint_url) = endpoint_url {
+            config = config.endpoint_url(endpoint_url);
+        }
+        S3Client {
+            settings: S3Settings {
+                force_path_style,
+                ..Default::default()
+            },
+            config: OnceCell::new_with(Some(config.build())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_url() -> Result<()> {
+        let location = S3Location {
+            bucket: "invoices".to_string(),
+            key: "2024/a b+c.pdf".to_string(),
+        };
+        assert_eq!(
+            s3_client(false, None).object_url(&location).await?.as_str(),
+            "https://invoices.s3.eu-central-1.amazonaws.com/2024/a%20b%2Bc.pdf"
+        );
+        assert_eq!(
+            s3_client(true, Some("http://localhost:9000/"))
+                .object_url(&location)
+                .await?
+                .as_str(),
+            "http://localhost:9000/invoices/2024/a%20b%2Bc.pdf"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signed_put_and_get() -> Result<()> {
+        let location = S3Location {
+            bucket: env::var("S3_BUCKET").unwrap(),
+            key: "test signed/key.json".to_string(),
+        };
+        let s3_client = S3Client::new(S3Settings {
+            force_path_style: true,
+            ..Default::default()
+        })?;
+        let reqwest_client = reqwest::Client::new();
+
+        let tempfile = TempFile::new().await?;
+        tokio::fs::write(tempfile.file_path(), b"{\"a\": 1}").await?;
+        super::upload_object(
+            &reqwest_client,
+            &Default::default(),
+            &s3_client,
+            tempfile,
+            mime::APPLICATION_JSON,
+            &location,
+            UploadIntegrity::Sha256,
+        )
+        .await?;
+
+        let (_url, _headers, body) = s3_client
+            .get_object(&reqwest_client, &Default::default(), &location)
+            .await?;
+        assert_eq!(body, b"{\"a\": 1}");
+
+        // cleanup
+        let _ = remove_bucket_key(&location.bucket, &location.key).await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_composite_etag() {
+        use md5::{Digest, Md5};
+
+        let part_md5s = [Md5::digest(b"hello "), Md5::digest(b"world")].concat();
+        assert_eq!(
+            super::composite_etag(&part_md5s, 2),
+            "e09e4fd6265b36115fe3db32df945d84-2"
+        );
+
+        let xml = "<CompleteMultipartUploadResult><Key>a.pdf</Key>\
+            <ETag>&quot;e09e4fd6265b36115fe3db32df945d84-2&quot;</ETag>\
+            </CompleteMultipartUploadResult>";
+        assert_eq!(
+            super::xml_element(xml, "ETag"),
+            Some("&quot;e09e4fd6265b36115fe3db32df945d84-2&quot;")
+        );
+        assert_eq!(super::xml_element(xml, "Error"), None);
+    }
+
+    #[test]
+    fn test_min_part_size() {
+        let settings = |part_size| S3Settings {
+            part_size,
+            ..Default::default()
+        };
+        assert!(S3Client::new(settings(5 * 1024 * 1024)).is_ok());
+        assert!(S3Client::new(settings(5 * 1024 * 1024 - 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload() -> Result<()> {
+        let location = S3Location {
+            bucket: env::var("S3_BUCKET").unwrap(),
+            key: "test-multipart.txt".to_string(),
+        };
+        let s3_client = S3Client::new(S3Settings {
+            force_path_style: true,
+            part_size: 5 * 1024 * 1024,
+        })?;
+        let reqwest_client = reqwest::Client::new();
+
+        let tempfile = TempFile::new().await?;
+        tokio::fs::write(tempfile.file_path(), b"Test data\n".repeat(1_200_000)).await?;
+        super::upload_object(
+            &reqwest_client,
+            &Default::default(),
+            &s3_client,
+            tempfile,
+            mime::TEXT_PLAIN,
+            &location,
+            UploadIntegrity::Etag,
+        )
+        .await?;
+
+        let (_url, _headers, body) = s3_client
+            .get_object(&reqwest_client, &Default::default(), &location)
+            .await?;
+        assert_eq!(body.len(), 12_000_000);
+
+        // cleanup
+        let _ = remove_bucket_key(&location.bucket, &location.key).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_integrity() -> Result<()> {
+        let mut tempfile = TempFile::new().await?;
+        tokio::fs::write(tempfile.file_path(), b"Test data\n").await?;
+        let checksums = Checksums::of_file(&mut tempfile).await?;
+        assert_eq!(
+            hex::encode(&checksums.md5),
+            "0c8263ef81be7da66923df494a259053"
+        );
+
+        assert!(
+            UploadIntegrity::Etag
+                .request_headers(&checksums)?
+                .is_empty()
+        );
+        let headers = UploadIntegrity::ContentMd5.request_headers(&checksums)?;
+        assert_eq!(headers["content-md5"], "DIJj74G+faZpI99JSiWQUw==");
+        let headers = UploadIntegrity::Sha256.request_headers(&checksums)?;
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("x-amz-checksum-sha256"));
+        assert!(
+            UploadIntegrity::None
+                .request_headers(&checksums)?
+                .is_empty()
+        );
+
+        let integrity: UploadIntegrity = serde_json::from_str(r#""opaque_etag""#)?;
+        assert_eq!(integrity, UploadIntegrity::OpaqueEtag);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_retries_request_errors() -> Result<()> {
+        // the connection is closed without a response
+        let url = crate::http::test::serve(vec![
+            "",
+            "HTTP/1.1 200 OK\r\nETag: \"0c8263ef81be7da66923df494a259053\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let settings = crate::http::HttpSettings {
+            retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        let tempfile = TempFile::new().await?;
+        tokio::fs::write(tempfile.file_path(), b"Test data\n").await?;
+        super::upload_file(
+            &settings.client(),
+            &settings,
+            tempfile,
+            mime::TEXT_PLAIN,
+            url,
+            UploadIntegrity::Etag,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_upload_retries() -> Result<()> {
+        let url = crate::http::test::serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nETag: \"0c8263ef81be7da66923df494a259053\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let settings = crate::http::HttpSettings {
+            retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        let tempfile = TempFile::new().await?;
+        tokio::fs::write(tempfile.file_path(), b"Test data\n").await?;
+        super::upload_file(
+            &settings.client(),
+            &settings,
+            tempfile,
+            mime::TEXT_PLAIN,
+            url.clone(),
+            UploadIntegrity::Etag,
+        )
+        .await?;
+
+        let url = crate::http::test::serve(vec![
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let tempfile = TempFile::new().await?;
+        let err = super::upload_file(
+            &settings.client(),
+            &settings,
+            tempfile,
+            mime::TEXT_PLAIN,
+            url,
+            UploadIntegrity::Etag,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("403"));
+        Ok(())
+    }
 }
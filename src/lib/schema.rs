@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path};
+
+use anyhow::{ensure, Context, Result};
+use foundations::telemetry::log::debug;
+use serde::Serialize;
+
+use crate::TemplateRef;
+
+/// A single place where the data does not match the template's schema.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Violation {
+    /// JSON pointer to the offending value, e.g. `/customer/name`.
+    pub path: String,
+    pub message: String,
+}
+
+/// The data given to a template does not match its schema.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct InvalidDataError {
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for InvalidDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Data does not match the template schema:")?;
+        for violation in &self.violations {
+            write!(f, "\n  {}: {}", violation.path, violation.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidDataError {}
+
+/// Validate `data` against the JSON schema next to the template (e.g. `invoice.mkiv.schema.json`),
+/// if there is one.
+pub async fn validate(
+    templates_path: &Path,
+    template: &TemplateRef,
+    data: &HashMap<String, minijinja::Value>,
+) -> Result<()> {
+    // the schema must not be read from outside the templates
+    ensure!(
+        Path::new(template.as_ref())
+            .components()
+            .all(|c| matches!(c, Component::Normal(_))),
+        "Invalid template {}",
+        template.as_ref()
+    );
+    let schema_path = templates_path.join(format!("{}.schema.json", template.as_ref()));
+    let schema = match tokio::fs::read(&schema_path).await {
+        Ok(schema) => schema,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Cannot read schema {}", schema_path.display()));
+        }
+    };
+    debug!("validating data"; "schema" => schema_path.to_str());
+
+    let schema: serde_json::Value = serde_json::from_slice(&schema)
+        .with_context(|| format!("Invalid JSON in schema {}", schema_path.display()))?;
+    let validator = jsonschema::validator_for(&schema)
+        .with_context(|| format!("Invalid schema {}", schema_path.display()))?;
+
+    let instance = serde_json::to_value(data).context("Cannot convert data to JSON")?;
+    let violations: Vec<Violation> = validator
+        .iter_errors(&instance)
+        .map(|e| Violation {
+            path: e.instance_path().to_string(),
+            message: e.to_string(),
+        })
+        .collect();
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidDataError { violations }.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_validate() -> Result<()> {
+        let dir = TempDir::new().await?;
+        tokio::fs::write(
+            dir.dir_path().join("invoice.mkiv.schema.json"),
+            r#"{
+                "type": "object",
+                "required": ["customer"],
+                "properties": {
+                    "customer": {"type": "object", "required": ["name"]},
+                    "number": {"type": "integer"}
+                }
+            }"#,
+        )
+        .await?;
+
+        let data = HashMap::from([
+            (
+                "customer".to_string(),
+                minijinja::Value::from_serialize(serde_json::json!({"city": "Berlin"})),
+            ),
+            ("number".to_string(), minijinja::Value::from("42")),
+        ]);
+        let err = validate(dir.dir_path(), &TemplateRef::from("invoice.mkiv".to_string()), &data)
+            .await
+            .unwrap_err()
+            .downcast::<InvalidDataError>()?;
+
+        let mut paths: Vec<&str> = err.violations.iter().map(|v| v.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, ["/customer", "/number"]);
+
+        // templates without schema are not validated
+        validate(dir.dir_path(), &TemplateRef::from("letter.mkiv".to_string()), &data).await?;
+
+        for template in ["../invoice.mkiv", "/etc/invoice.mkiv", "a/../../invoice.mkiv"] {
+            let template = TemplateRef::from(template.to_string());
+            let err = validate(dir.dir_path(), &template, &data).await.unwrap_err();
+            assert!(err.to_string().starts_with("Invalid template"));
+        }
+        Ok(())
+    }
+}
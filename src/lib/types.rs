@@ -1,52 +1,197 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use anyhow::{bail, Context, Result};
 use mime_guess::{mime, Mime, MimeGuess};
 use nutype::nutype;
-use reqwest::header;
-use reqwest::Url;
-use serde::Deserialize;
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Method, Url};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::io::{stdin, AsyncReadExt, BufReader};
 
+use crate::http::{self, HttpDelivery, HttpSettings, SecretValue};
+use crate::metadata::JobMetadata;
+use crate::s3::{S3Client, S3Location, UploadIntegrity};
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
 pub struct RenderJob {
     pub template: TemplateRef,
+    /// One or more destinations. By default, the file is returned as buffer.
+    #[serde(default = "default_output", deserialize_with = "one_or_many")]
+    pub output: Vec<OutputRef>,
+    /// Pattern for the name of the produced file, rendered with the job data, e.g.
+    /// `invoice-{{ number }}.pdf`.
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Keep the rendered source and the compiler's files if compiling fails. Only possible if
+    /// the server is configured to keep build artifacts.
+    #[serde(default)]
+    pub keep_artifacts: bool,
+    /// Compile with this backend instead of the one for the template's extension.
     #[serde(default)]
-    pub output: OutputRef,
+    pub backend: Option<String>,
     pub inputs: Vec<Input>,
+    #[serde(default)]
+    pub merge: MergeStrategy,
 }
 
-#[nutype(derive(AsRef, From, FromStr, Clone, Debug, Deserialize, Eq, PartialEq))]
-pub struct TemplateRef(String);
+/// How the data of later inputs is combined with earlier ones.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// Later top-level keys replace earlier ones.
+    #[default]
+    Replace,
+    /// Objects are merged recursively, everything else is replaced.
+    DeepMerge,
+    /// Like `DeepMerge`, but arrays are concatenated.
+    AppendArrays,
+}
 
-impl TemplateRef {
-    const COMPILE_EXTENSIONS: [&'static str; 2] = ["tex", "mkiv"];
+impl MergeStrategy {
+    pub fn merge(
+        self,
+        data: &mut HashMap<String, minijinja::Value>,
+        other: HashMap<String, minijinja::Value>,
+    ) {
+        for (key, value) in other {
+            let value = match (self, data.remove(&key)) {
+                (MergeStrategy::Replace, _) | (_, None) => value,
+                (_, Some(old)) => self.merge_values(old, value),
+            };
+            data.insert(key, value);
+        }
+    }
 
-    pub fn should_compile(&self) -> bool {
-        self.extension()
-            .map(|ext| TemplateRef::COMPILE_EXTENSIONS.contains(&ext))
-            .unwrap_or(false)
+    fn merge_values(self, old: minijinja::Value, new: minijinja::Value) -> minijinja::Value {
+        use minijinja::value::ValueKind;
+
+        match (old.kind(), new.kind()) {
+            (ValueKind::Map, ValueKind::Map) => {
+                let mut merged: Vec<(minijinja::Value, minijinja::Value)> = old
+                    .try_iter()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|k| old.get_item(&k).ok().map(|v| (k, v)))
+                    .collect();
+                for key in new.try_iter().into_iter().flatten() {
+                    let value = new.get_item(&key).unwrap_or_default();
+                    match merged.iter_mut().find(|(k, _)| *k == key) {
+                        Some((_, old_value)) => {
+                            *old_value = self.merge_values(old_value.clone(), value)
+                        }
+                        None => merged.push((key, value)),
+                    }
+                }
+                minijinja::Value::from_iter(merged)
+            }
+            (ValueKind::Seq, ValueKind::Seq) if self == MergeStrategy::AppendArrays => old
+                .try_iter()
+                .into_iter()
+                .flatten()
+                .chain(new.try_iter().into_iter().flatten())
+                .collect(),
+            _ => new,
+        }
+    }
+}
+
+impl FromStr for MergeStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "replace" => Self::Replace,
+            "deep_merge" => Self::DeepMerge,
+            "append_arrays" => Self::AppendArrays,
+            _ => bail!("Unknown merge strategy {}", s),
+        })
     }
+}
 
+#[nutype(derive(AsRef, From, FromStr, Clone, Debug, Deserialize, Eq, PartialEq))]
+pub struct TemplateRef(String);
+
+impl TemplateRef {
     pub fn extension(&self) -> Option<&str> {
         Path::new(self.as_ref())
             .extension()
             .map(|ext| ext.to_str().unwrap())
     }
 
+    /// The type of the rendered template, if it is not compiled.
     pub fn mime_type(&self) -> Mime {
-        if self.should_compile() {
-            mime::APPLICATION_PDF
-        } else {
-            self.extension()
-                .and_then(|ext| MimeGuess::from_ext(ext).first())
-                .unwrap_or(mime::TEXT_PLAIN)
+        self.extension()
+            .and_then(|ext| MimeGuess::from_ext(ext).first())
+            .unwrap_or(mime::TEXT_PLAIN)
+    }
+}
+
+fn default_output() -> Vec<OutputRef> {
+    vec![OutputRef::Buffer]
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<OutputRef>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        Many(Vec<OutputRef>),
+        One(OutputRef),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::Many(outputs) => outputs,
+        OneOrMany::One(output) => vec![output],
+    })
+}
+
+/// The result of a job: the file if it was requested as buffer, and the report about it.
+#[derive(Debug, Eq, PartialEq)]
+pub struct JobOutput {
+    pub buffer: Option<OutputBuffer>,
+    pub report: JobReport,
+}
+
+impl JobOutput {
+    /// Fail if the file could not be written to any of the destinations.
+    pub fn ensure_written(&self) -> Result<()> {
+        let errors: Vec<String> = self
+            .report
+            .outputs
+            .iter()
+            .filter_map(|output| {
+                let error = output.error.as_ref()?;
+                Some(format!("{}: {}", output.target, error))
+            })
+            .collect();
+        if !errors.is_empty() {
+            bail!("Could not write output to {}", errors.join(", "));
         }
+        Ok(())
     }
 }
 
+/// What is known about a job's file, and whether it could be written to the destinations.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct JobReport {
+    pub metadata: JobMetadata,
+    pub outputs: Vec<OutputResult>,
+}
+
+/// Whether the file was written to a destination.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OutputResult {
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time spent writing or uploading the file.
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct OutputBuffer {
     pub buffer: Vec<u8>,
@@ -57,35 +202,467 @@ pub struct OutputBuffer {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 #[serde(untagged)]
 pub enum Input {
+    Spec(InputSpec),
     FileRef(FileRef),
+    Literal(LiteralInput),
     Inline(HashMap<String, minijinja::Value>),
 }
 
+/// Data given as text in the job, e.g. Markdown. Unknown fields make it inline data instead.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LiteralInput {
+    pub literal: String,
+    #[serde(rename = "type")]
+    pub mime_type: String,
+}
+
+/// An input with additional settings. Unknown fields make it inline data instead.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InputSpec {
+    /// Mount the input's data under this variable instead of merging it into the top level.
+    pub name: Option<String>,
+    /// Parse the input in this format, regardless of file extension or content type.
+    pub format: Option<InputFormat>,
+    /// Additional request headers for URL inputs.
+    #[serde(default)]
+    pub headers: HashMap<String, SecretValue>,
+    /// Sent as `Authorization: Bearer <token>` for URL inputs.
+    pub bearer_token: Option<SecretValue>,
+    pub source: Box<Input>,
+}
+
+/// Settings of an `InputSpec` that apply when reading its source.
+#[derive(Debug, Default)]
+struct ReadOptions<'a> {
+    name: Option<&'a str>,
+    format: Option<InputFormat>,
+    headers: HeaderMap,
+}
+
 impl Input {
     pub async fn read_into_env(
         self,
         reqwest_client: &reqwest::Client,
+        http_settings: &HttpSettings,
+        s3_client: &S3Client,
+    ) -> Result<HashMap<String, minijinja::Value>> {
+        match self {
+            Input::Spec(InputSpec {
+                name,
+                format,
+                headers,
+                bearer_token,
+                source,
+            }) => {
+                let headers = match &*source {
+                    Input::FileRef(FileRef::Url(url)) => {
+                        http::request_headers(&headers, bearer_token.as_ref(), http_settings, url)
+                            .await?
+                    }
+                    _ if headers.is_empty() && bearer_token.is_none() => HeaderMap::new(),
+                    _ => bail!("Headers are only supported for URL inputs"),
+                };
+                let options = ReadOptions {
+                    name: name.as_deref(),
+                    format,
+                    headers,
+                };
+                match *source {
+                    Input::Spec(_) => bail!("Input specs cannot be nested"),
+                    source => {
+                        source
+                            .read_with(reqwest_client, http_settings, s3_client, &options)
+                            .await
+                    }
+                }
+            }
+            input => {
+                input
+                    .read_with(
+                        reqwest_client,
+                        http_settings,
+                        s3_client,
+                        &Default::default(),
+                    )
+                    .await
+            }
+        }
+    }
+
+    async fn read_with(
+        self,
+        reqwest_client: &reqwest::Client,
+        http_settings: &HttpSettings,
+        s3_client: &S3Client,
+        options: &ReadOptions<'_>,
     ) -> Result<HashMap<String, minijinja::Value>> {
+        let ReadOptions { name, format, .. } = *options;
         match self {
-            Input::FileRef(fileref) => fileref.read_into_env(reqwest_client).await,
-            Input::Inline(v) => Ok(v),
+            Input::Spec(_) => unreachable!("specs are unpacked in read_into_env"),
+            Input::FileRef(fileref) => {
+                fileref
+                    .read_with(reqwest_client, http_settings, s3_client, options)
+                    .await
+            }
+            Input::Literal(LiteralInput { literal, mime_type }) => {
+                let mime_type: Mime = mime_type
+                    .parse()
+                    .with_context(|| format!("Invalid literal input type {}", mime_type))?;
+                let format = format
+                    .or_else(|| InputFormat::from_mime(&mime_type))
+                    .with_context(|| format!("Unsupported literal input type {}", mime_type))?;
+                let path = Path::new(mime_type.subtype().as_str());
+                format.parse(name, path, literal.into())
+            }
+            Input::Inline(v) => Ok(mount(name, v)),
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    /// Parse `[format:]file-or-url`, e.g. `yaml:-` reads YAML from stdin, or a JSON input spec
+    /// like in a job.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim_start().starts_with('{') {
+            return serde_json::from_str(s).context("Invalid input spec");
+        }
+        if let Some((format, source)) = s.split_once(':')
+            && let Ok(format) = InputFormat::from_str(format)
+        {
+            return Ok(Input::Spec(InputSpec {
+                name: None,
+                format: Some(format),
+                headers: Default::default(),
+                bearer_token: None,
+                source: Box::new(Input::FileRef(FileRef::from_str(source)?)),
+            }));
+        }
+        FileRef::from_str(s).map(Input::FileRef)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFormat {
+    Json,
+    Yaml,
+    Csv,
+    Markdown,
+}
+
+impl InputFormat {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "csv" => Some(Self::Csv),
+            "md" | "markdown" => Some(Self::Markdown),
+            _ => None,
+        }
+    }
+
+    /// Supports structured syntax suffixes (e.g. `application/vnd.api+json`) and common aliases
+    /// (e.g. `text/yaml` or `application/x-yaml`).
+    pub fn from_mime(mime_type: &Mime) -> Option<Self> {
+        match mime_type.suffix().map(|suffix| suffix.as_str()) {
+            Some("json") => return Some(Self::Json),
+            Some("yaml") => return Some(Self::Yaml),
+            _ => {}
+        }
+
+        // Note: it's not possible to use `mime::JSON`, because `mime::YAML` does not exist
+        match (mime_type.type_(), mime_type.subtype().as_str()) {
+            (mime::APPLICATION | mime::TEXT, "json" | "x-json") => Some(Self::Json),
+            (mime::APPLICATION | mime::TEXT, "yaml" | "x-yaml") => Some(Self::Yaml),
+            (mime::APPLICATION | mime::TEXT, "csv" | "x-csv" | "comma-separated-values") => {
+                Some(Self::Csv)
+            }
+            (mime::TEXT, "markdown" | "x-markdown") => Some(Self::Markdown),
+            _ => None,
         }
     }
+
+    /// Determine the format of a downloaded file from its content type. Falls back to the URL's
+    /// file extension if the content type is missing or generic (as e.g. `binary/octet-stream`
+    /// from S3), and to JSON if there is no content type at all.
+    pub fn from_url(url: &Url, content_type: Option<&Mime>) -> Result<Self> {
+        let is_generic = |mime_type: &Mime| {
+            mime_type.subtype() == mime::OCTET_STREAM
+                || *mime_type == mime::TEXT_PLAIN
+                || mime_type.essence_str() == "application/binary"
+        };
+        let from_extension = || {
+            Path::new(url.path())
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(Self::from_extension)
+        };
+
+        match content_type {
+            Some(content_type) if !is_generic(content_type) => Self::from_mime(content_type)
+                .with_context(|| format!("Unsupported input file {}", content_type)),
+            Some(content_type) => from_extension()
+                .with_context(|| format!("Unsupported input file {} ({})", url, content_type)),
+            None => Ok(from_extension().unwrap_or(Self::Json)),
+        }
+    }
+
+    /// Guess the format from the content. Markdown is never detected.
+    pub fn sniff(bytes: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        // arrays are JSON as well, so they are not mistaken for CSV
+        if text.starts_with(['{', '[']) {
+            Self::Json
+        } else if let Ok(serde_yaml::Value::Mapping(_)) = serde_yaml::from_str(text) {
+            Self::Yaml
+        } else {
+            Self::Csv
+        }
+    }
+
+    /// Parse `bytes` into template variables. `path` is used to name CSV and Markdown data if
+    /// no `name` is given.
+    fn parse(
+        self,
+        name: Option<&str>,
+        path: &Path,
+        bytes: Vec<u8>,
+    ) -> Result<HashMap<String, minijinja::Value>> {
+        Ok(match self {
+            Self::Json => mount(name, serde_json::from_slice(&bytes)?),
+            Self::Yaml => mount(name, serde_yaml::from_slice(&bytes)?),
+            Self::Csv => read_csv(variable_name(name, path)?, &bytes)?,
+            Self::Markdown => read_markdown(
+                variable_name(name, path)?,
+                String::from_utf8(bytes).context("Markdown is not valid UTF-8")?,
+            ),
+        })
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_extension(s).with_context(|| format!("Unknown input format {}", s))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 #[serde(untagged)]
 pub enum OutputRef {
+    Spec(OutputSpec),
     File(FileRef),
     Buffer,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for OutputRef {
     fn default() -> Self {
         Self::Buffer
     }
 }
 
+/// An output with additional settings.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct OutputSpec {
+    pub target: FileRef,
+    /// How to verify uploads to URLs and S3. By default, the ETag must be the MD5 of the file,
+    /// unless the file is delivered with custom requests.
+    pub integrity: Option<UploadIntegrity>,
+    /// Deliver the file to a URL with this method instead of a plain upload.
+    pub method: Option<HttpMethod>,
+    /// Additional request headers for URL outputs.
+    #[serde(default)]
+    pub headers: HashMap<String, SecretValue>,
+    /// Sent as `Authorization: Bearer <token>` for URL outputs.
+    pub bearer_token: Option<SecretValue>,
+    /// Post the file as `multipart/form-data` after these fields, e.g. for S3 presigned POST
+    /// policies. The fields are sent in the given order.
+    #[serde(default, deserialize_with = "ordered_fields")]
+    pub form: Option<Vec<(String, String)>>,
+    /// Write the job report to `<file>.json` next to a file output.
+    #[serde(default)]
+    pub metadata_sidecar: bool,
+    #[serde(flatten)]
+    pub file: FileOptions,
+}
+
+/// How local files are written.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub struct FileOptions {
+    /// Create missing parent directories.
+    #[serde(default)]
+    pub create_dirs: bool,
+    /// Replace an existing file. Otherwise, the output fails if the file exists.
+    #[serde(default = "default_overwrite")]
+    pub overwrite: bool,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            create_dirs: false,
+            overwrite: true,
+        }
+    }
+}
+
+fn default_overwrite() -> bool {
+    true
+}
+
+/// The entries of an object in the order they are given, unlike a `HashMap`.
+fn ordered_fields<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<(String, String)>>, D::Error> {
+    struct FieldsVisitor;
+
+    impl<'de> Visitor<'de> for FieldsVisitor {
+        type Value = Vec<(String, String)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an object of form fields")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut fields = vec![];
+            while let Some(field) = map.next_entry()? {
+                fields.push(field);
+            }
+            Ok(fields)
+        }
+    }
+
+    deserializer.deserialize_map(FieldsVisitor).map(Some)
+}
+
+impl From<FileRef> for OutputSpec {
+    fn from(target: FileRef) -> Self {
+        OutputSpec {
+            target,
+            integrity: None,
+            method: None,
+            headers: Default::default(),
+            bearer_token: None,
+            form: None,
+            metadata_sidecar: false,
+            file: Default::default(),
+        }
+    }
+}
+
+/// Methods for delivering outputs to URLs.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    Put,
+    Post,
+}
+
+impl OutputSpec {
+    /// The request to deliver the file with, unless it is a plain upload.
+    pub async fn http_delivery(&self, settings: &HttpSettings) -> Result<Option<HttpDelivery>> {
+        if self.method.is_none()
+            && self.headers.is_empty()
+            && self.bearer_token.is_none()
+            && self.form.is_none()
+        {
+            return Ok(None);
+        }
+        let FileRef::Url(url) = &self.target else {
+            bail!("Methods, headers and forms are only supported for URL outputs");
+        };
+
+        let method = match (self.method, &self.form) {
+            (Some(HttpMethod::Put), Some(_)) => bail!("Forms can only be posted"),
+            (Some(HttpMethod::Put), None) | (None, None) => Method::PUT,
+            (Some(HttpMethod::Post), _) | (None, Some(_)) => Method::POST,
+        };
+        Ok(Some(HttpDelivery {
+            method,
+            headers: http::request_headers(
+                &self.headers,
+                self.bearer_token.as_ref(),
+                settings,
+                url,
+            )
+            .await?,
+            form: self.form.clone(),
+        }))
+    }
+}
+
+impl OutputRef {
+    /// Fail if file options are given for an output that is no file.
+    pub fn validate(&self) -> Result<()> {
+        let OutputRef::Spec(spec) = self else {
+            return Ok(());
+        };
+        let is_file = matches!(&spec.target, FileRef::File(path) if path.as_os_str() != "-");
+        if !is_file && (spec.metadata_sidecar || spec.file != FileOptions::default()) {
+            bail!("Metadata sidecars, create_dirs and overwrite only apply to file outputs");
+        }
+        Ok(())
+    }
+
+    /// How the output is written, if it is a file.
+    pub fn file_options(&self) -> FileOptions {
+        match self {
+            OutputRef::Spec(spec) => spec.file,
+            _ => Default::default(),
+        }
+    }
+
+    /// The path of the metadata sidecar, if one is requested for this output.
+    pub fn sidecar_path(&self) -> Option<PathBuf> {
+        match self {
+            OutputRef::Spec(OutputSpec {
+                target: FileRef::File(path),
+                metadata_sidecar: true,
+                ..
+            }) if path.as_os_str() != "-" => {
+                let mut sidecar = path.clone().into_os_string();
+                sidecar.push(".json");
+                Some(sidecar.into())
+            }
+            _ => None,
+        }
+    }
+
+    /// The output with `filename` appended to a target that is a directory or key prefix, i.e.
+    /// ends with `/`.
+    pub fn with_filename(&self, filename: &str) -> OutputRef {
+        let mut output = self.clone();
+        match &mut output {
+            OutputRef::Spec(OutputSpec { target, .. }) | OutputRef::File(target) => match target {
+                FileRef::File(path) if path.as_os_str().to_string_lossy().ends_with('/') => {
+                    path.push(filename)
+                }
+                FileRef::S3(location) if location.key.ends_with('/') => {
+                    location.key.push_str(filename)
+                }
+                _ => {}
+            },
+            OutputRef::Buffer => {}
+        }
+        output
+    }
+
+    /// Where the output is written to, unless it is returned as buffer.
+    pub fn target(&self) -> Option<&FileRef> {
+        match self {
+            OutputRef::Spec(OutputSpec { target, .. }) | OutputRef::File(target) => Some(target),
+            OutputRef::Buffer => None,
+        }
+    }
+}
+
 impl FromStr for OutputRef {
     type Err = anyhow::Error;
 
@@ -95,9 +672,11 @@ impl FromStr for OutputRef {
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
-#[serde(from = "&str")]
+#[serde(try_from = "&str")]
 pub enum FileRef {
     Url(reqwest::Url),
+    /// An object in S3, accessed with the configured AWS credentials.
+    S3(S3Location),
     File(PathBuf),
 }
 
@@ -105,7 +684,31 @@ impl FileRef {
     pub async fn read_into_env(
         &self,
         reqwest_client: &reqwest::Client,
+        http_settings: &HttpSettings,
+        s3_client: &S3Client,
     ) -> Result<HashMap<String, minijinja::Value>> {
+        self.read_with(
+            reqwest_client,
+            http_settings,
+            s3_client,
+            &Default::default(),
+        )
+        .await
+    }
+
+    /// Read the file, mounting its data under `name` if given.
+    ///
+    /// CSV and Markdown files are stored under `name` instead of their file stem. Without an
+    /// explicit `format`, it is derived from the file extension or content type. Stdin is
+    /// sniffed for JSON, YAML or CSV.
+    async fn read_with(
+        &self,
+        reqwest_client: &reqwest::Client,
+        http_settings: &HttpSettings,
+        s3_client: &S3Client,
+        options: &ReadOptions<'_>,
+    ) -> Result<HashMap<String, minijinja::Value>> {
+        let ReadOptions { name, format, .. } = *options;
         match self {
             FileRef::File(filename) => {
                 if filename.as_os_str() == "-" {
@@ -115,50 +718,162 @@ impl FileRef {
                         .read_to_end(&mut bytes)
                         .await
                         .context("Cannot read from stdin")?;
-                    Ok(serde_json::from_slice(&bytes)?)
+                    let format = format.unwrap_or_else(|| InputFormat::sniff(&bytes));
+                    format.parse(name, Path::new("stdin"), bytes)
                 } else {
                     let bytes = tokio::fs::read(filename).await.with_context(|| {
                         format!("Cannot open input file {}", filename.display())
                     })?;
-                    let data = match filename.extension().and_then(|s| s.to_str()) {
-                        Some("json") => serde_json::from_slice(&bytes)?,
-                        Some("yaml") => serde_yaml::from_slice(&bytes)?,
-                        _ => bail!("Unsupported input file {}", filename.display()),
-                    };
-                    Ok(data)
+                    let format = format
+                        .or_else(|| {
+                            filename
+                                .extension()
+                                .and_then(|s| s.to_str())
+                                .and_then(InputFormat::from_extension)
+                        })
+                        .with_context(|| {
+                            format!("Unsupported input file {}", filename.display())
+                        })?;
+                    format.parse(name, filename, bytes)
                 }
             }
             FileRef::Url(url) => {
-                let res = reqwest_client
-                    .get(url.as_ref())
-                    .send()
-                    .await?
-                    .error_for_status()?;
-
-                let content_type = res
-                    .headers()
-                    .get(header::CONTENT_TYPE)
-                    .and_then(|v| v.to_str().ok())
-                    .and_then(|v| v.parse::<Mime>().ok())
-                    .unwrap_or(mime::APPLICATION_JSON);
-
-                let bytes = res.bytes().await?;
-
-                // Note: it's not possible to use `mime::JSON`, because `mime::YAML` does not exist
-                let data = match (content_type.type_(), content_type.subtype().as_str()) {
-                    (mime::APPLICATION, "json") => serde_json::from_slice(&bytes)?,
-                    (mime::APPLICATION, "yaml") => serde_yaml::from_slice(&bytes)?,
-                    _ => bail!("Unsupported input file {}", content_type),
-                };
-                Ok(data)
+                let (headers, bytes) =
+                    http::get(reqwest_client, http_settings, url, &options.headers)
+                        .await
+                        .with_context(|| format!("Cannot fetch input {}", url))?;
+                read_response(&headers, bytes, url, options)
             }
+            FileRef::S3(location) => {
+                let (url, headers, bytes) = s3_client
+                    .get_object(reqwest_client, http_settings, location)
+                    .await
+                    .with_context(|| format!("Cannot fetch input {}", location))?;
+                read_response(&headers, bytes, &url, options)
+            }
+        }
+    }
+}
+
+/// Parse a remote input.
+fn read_response(
+    headers: &HeaderMap,
+    bytes: Vec<u8>,
+    url: &Url,
+    options: &ReadOptions<'_>,
+) -> Result<HashMap<String, minijinja::Value>> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Mime>().ok());
+
+    let format = match options.format {
+        Some(format) => format,
+        None => InputFormat::from_url(url, content_type.as_ref())?,
+    };
+    format.parse(options.name, Path::new(url.path()), bytes)
+}
+
+/// Mount `data` under `name`, if given, or leave it to be merged into the top level.
+fn mount(
+    name: Option<&str>,
+    data: HashMap<String, minijinja::Value>,
+) -> HashMap<String, minijinja::Value> {
+    match name {
+        Some(name) => HashMap::from([(name.to_string(), minijinja::Value::from_iter(data))]),
+        None => data,
+    }
+}
+
+/// Use the given `name` or derive a template variable name from the file stem, e.g.
+/// `line-items.csv` becomes `line_items`.
+fn variable_name(name: Option<&str>, path: &Path) -> Result<String> {
+    if let Some(name) = name {
+        return Ok(name.to_string());
+    }
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|s| !s.is_empty())
+        .with_context(|| format!("Cannot derive variable name from {}", path.display()))?;
+    Ok(stem
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect())
+}
+
+/// Parse a CSV file with a header row into a list of row objects, stored under `name`.
+///
+/// All cells are kept as strings; use e.g. the `float` filter for calculations.
+fn read_csv(name: String, bytes: &[u8]) -> Result<HashMap<String, minijinja::Value>> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let (delimiter, quote) = sniff_csv_dialect(bytes);
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .quote(quote)
+        .trim(csv::Trim::Headers)
+        .from_reader(bytes);
+
+    let headers = reader.headers().context("Cannot read CSV header")?.clone();
+    let rows = reader
+        .records()
+        .map(|record| {
+            let record = record.context("Cannot read CSV record")?;
+            Ok(headers
+                .iter()
+                .zip(record.iter())
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect())
+        })
+        .collect::<Result<Vec<minijinja::Value>>>()?;
+
+    Ok(HashMap::from([(name, minijinja::Value::from(rows))]))
+}
+
+/// Store Markdown under `name` as an object with the raw `markdown` and the converted `context`
+/// markup.
+fn read_markdown(name: String, markdown: String) -> HashMap<String, minijinja::Value> {
+    let context = crate::markdown::to_context(&markdown);
+    let value = minijinja::Value::from_iter([("markdown", markdown), ("context", context)]);
+    HashMap::from([(name, value)])
+}
+
+/// Guess the delimiter (`,`, `;`, tab or `|`) and quote character (`"` or `'`) from the header
+/// line. Defaults to `,` and `"`.
+fn sniff_csv_dialect(bytes: &[u8]) -> (u8, u8) {
+    let header = bytes.split(|b| *b == b'\n').next().unwrap_or_default();
+    let delimiter = [b',', b';', b'\t', b'|']
+        .into_iter()
+        .rev()
+        .max_by_key(|d| header.iter().filter(|b| *b == d).count())
+        .unwrap();
+    let single_quoted = header
+        .split(|b| *b == delimiter)
+        .any(|field| field.starts_with(b"'"));
+    let quote = if single_quoted && !header.contains(&b'"') {
+        b'\''
+    } else {
+        b'"'
+    };
+    (delimiter, quote)
+}
+
+/// URLs are shown without query string, so the signatures of presigned URLs are not disclosed.
+impl fmt::Display for FileRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileRef::Url(url) => write!(f, "{}{}", url.origin().ascii_serialization(), url.path()),
+            FileRef::S3(location) => write!(f, "{}", location),
+            FileRef::File(path) => write!(f, "{}", path.display()),
         }
     }
 }
 
-impl From<&str> for FileRef {
-    fn from(value: &str) -> Self {
-        Self::from_str(value).unwrap()
+impl TryFrom<&str> for FileRef {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        Self::from_str(value)
     }
 }
 
@@ -166,9 +881,10 @@ impl FromStr for FileRef {
     type Err = anyhow::Error;
 
     /// This will parse the parameter and if it resembles a URL (i.e. reqwest can parse it) treat
-    /// it as URL, if not as filename.
+    /// it as URL, if not as filename. `s3://bucket/key` refers to an object in S3.
     fn from_str(str: &str) -> Result<Self> {
         Ok(match Url::parse(str) {
+            Ok(url) if url.scheme() == "s3" => FileRef::S3(S3Location::try_from(&url)?),
             Ok(url) => FileRef::Url(url),
             Err(_) => FileRef::File(Path::new(str).to_path_buf()),
         })
@@ -179,9 +895,11 @@ impl FromStr for FileRef {
 mod test {
     use crate::*;
     use minijinja::Value;
+    use std::path::{Path, PathBuf};
     use std::str::FromStr;
 
     #[test]
+    #[allow(clippy::needless_borrows_for_generic_args)]
     fn test_deserialization() {
         let sample = r#"{
             "template": "test.j2",
@@ -191,12 +909,350 @@ mod test {
         let parsed: RenderJob = serde_json::from_str(sample).unwrap();
         let renderjob = RenderJob {
             template: TemplateRef::from("test.j2".to_string()),
-            output: OutputRef::from_str("/test/file").unwrap(),
+            output: vec![OutputRef::from_str("/test/file").unwrap()],
             inputs: vec![Input::Inline(HashMap::from([(
                 "test".to_string(),
                 Value::from_serialize(&"value"),
             )]))],
+            merge: MergeStrategy::Replace,
+            filename: None,
+            keep_artifacts: false,
+            backend: None,
         };
         assert_eq!(parsed, renderjob);
     }
+
+    #[test]
+    fn test_deserialize_outputs() {
+        let sample = r#"{
+            "template": "test.j2",
+            "inputs": [],
+            "output": [
+                "https://archive.example.com/a.pdf?X-Amz-Signature=secret",
+                {"target": "s3://bucket/a.pdf", "integrity": "none"},
+                null
+            ]
+        }"#;
+        let parsed: RenderJob = serde_json::from_str(sample).unwrap();
+        assert_eq!(parsed.output.len(), 3);
+        assert_eq!(
+            parsed.output[0].target().unwrap().to_string(),
+            "https://archive.example.com/a.pdf"
+        );
+        assert_eq!(
+            parsed.output[1].target().unwrap().to_string(),
+            "s3://bucket/a.pdf"
+        );
+        assert_eq!(parsed.output[2], OutputRef::Buffer);
+
+        let parsed: RenderJob =
+            serde_json::from_str(r#"{"template": "test.j2", "inputs": []}"#).unwrap();
+        assert_eq!(parsed.output, vec![OutputRef::Buffer]);
+    }
+
+    #[tokio::test]
+    async fn test_http_delivery() {
+        let spec = |json: &str| serde_json::from_str::<OutputSpec>(json).unwrap();
+        let settings = Default::default();
+
+        let plain = spec(r#"{"target": "https://example.com/upload"}"#);
+        assert!(plain.http_delivery(&settings).await.unwrap().is_none());
+
+        let form =
+            spec(r#"{"target": "https://bucket.s3.amazonaws.com/", "form": {"key": "a.pdf"}}"#);
+        let delivery = form.http_delivery(&settings).await.unwrap().unwrap();
+        assert_eq!(delivery.method, reqwest::Method::POST);
+
+        let output: OutputRef = serde_json::from_str(
+            r#"{"target": "https://example.com/", "form": {"z": "1", "key": "2", "a": "3"}}"#,
+        )
+        .unwrap();
+        let OutputRef::Spec(ordered) = output else {
+            panic!("not a spec: {:?}", output);
+        };
+        let fields: Vec<_> = ordered
+            .form
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(fields, ["z", "key", "a"]);
+
+        let webdav = spec(
+            r#"{"target": "https://dav.example.com/a.pdf", "method": "PUT", "bearer_token": "t"}"#,
+        );
+        let delivery = webdav.http_delivery(&settings).await.unwrap().unwrap();
+        assert_eq!(delivery.method, reqwest::Method::PUT);
+        assert_eq!(delivery.headers[reqwest::header::AUTHORIZATION], "Bearer t");
+
+        let put_form = spec(r#"{"target": "https://example.com/", "method": "PUT", "form": {}}"#);
+        assert!(put_form.http_delivery(&settings).await.is_err());
+        let s3_headers = spec(r#"{"target": "s3://bucket/a.pdf", "headers": {"X-A": "b"}}"#);
+        assert!(s3_headers.http_delivery(&settings).await.is_err());
+    }
+
+    #[test]
+    fn test_sidecar_path() {
+        let output = |json: &str| serde_json::from_str::<OutputRef>(json).unwrap();
+
+        let spec = output(r#"{"target": "out/invoice.pdf", "metadata_sidecar": true}"#);
+        assert_eq!(
+            spec.sidecar_path(),
+            Some(PathBuf::from("out/invoice.pdf.json"))
+        );
+        assert_eq!(output(r#""out/invoice.pdf""#).sidecar_path(), None);
+        let stdout = output(r#"{"target": "-", "metadata_sidecar": true}"#);
+        assert_eq!(stdout.sidecar_path(), None);
+        let url = output(r#"{"target": "https://example.com/a.pdf", "metadata_sidecar": true}"#);
+        assert_eq!(url.sidecar_path(), None);
+        assert!(url.validate().is_err());
+    }
+
+    #[test]
+    fn test_with_filename() {
+        let target = |output: &str| {
+            OutputRef::from_str(output)
+                .unwrap()
+                .with_filename("invoice-42.pdf")
+                .target()
+                .map(ToString::to_string)
+        };
+        assert_eq!(target("out/").as_deref(), Some("out/invoice-42.pdf"));
+        assert_eq!(target("out/a.pdf").as_deref(), Some("out/a.pdf"));
+        assert_eq!(
+            target("s3://bucket/invoices/").as_deref(),
+            Some("s3://bucket/invoices/invoice-42.pdf")
+        );
+        assert_eq!(
+            target("s3://bucket/a.pdf").as_deref(),
+            Some("s3://bucket/a.pdf")
+        );
+        assert_eq!(OutputRef::Buffer.with_filename("a.pdf"), OutputRef::Buffer);
+    }
+
+    #[test]
+    fn test_file_options() {
+        let output = |json: &str| serde_json::from_str::<OutputRef>(json).unwrap();
+
+        let plain = output(r#""out/invoice.pdf""#);
+        assert_eq!(plain.file_options(), FileOptions::default());
+        assert!(plain.file_options().overwrite);
+
+        let spec =
+            output(r#"{"target": "out/invoice.pdf", "create_dirs": true, "overwrite": false}"#);
+        assert_eq!(
+            spec.file_options(),
+            FileOptions {
+                create_dirs: true,
+                overwrite: false
+            }
+        );
+        assert!(spec.validate().is_ok());
+
+        let s3 = output(r#"{"target": "s3://bucket/invoice.pdf", "overwrite": false}"#);
+        assert!(s3.validate().is_err());
+    }
+
+    #[test]
+    fn test_deserialize_named() {
+        let sample = r#"{"name": "customer", "source": "https://example.com/customer.json"}"#;
+        let parsed: Input = serde_json::from_str(sample).unwrap();
+        let input = Input::Spec(InputSpec {
+            name: Some("customer".to_string()),
+            format: None,
+            headers: Default::default(),
+            bearer_token: None,
+            source: Box::new(Input::FileRef(
+                FileRef::from_str("https://example.com/customer.json").unwrap(),
+            )),
+        });
+        assert_eq!(parsed, input);
+
+        // inline data that happens to have a `source` key
+        let sample = r#"{"source": "newsletter", "customer": {"name": "ACME"}}"#;
+        let parsed: Input = serde_json::from_str(sample).unwrap();
+        let Input::Inline(data) = parsed else {
+            panic!("not inline data: {:?}", parsed)
+        };
+        assert_eq!(data["source"], Value::from("newsletter"));
+        assert_eq!(
+            data["customer"].get_attr("name").unwrap(),
+            Value::from("ACME")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_named_inline() {
+        let input: Input =
+            serde_json::from_str(r#"{"name": "customer", "source": {"id": 1}}"#).unwrap();
+        let data = input
+            .read_into_env(
+                &reqwest::Client::new(),
+                &Default::default(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        let expected = Value::from_serialize(serde_json::json!({"id": 1}));
+        assert_eq!(data, HashMap::from([("customer".to_string(), expected)]));
+    }
+
+    #[test]
+    fn test_input_from_str() {
+        let input = Input::from_str("yaml:-").unwrap();
+        let expected = Input::Spec(InputSpec {
+            name: None,
+            format: Some(InputFormat::Yaml),
+            headers: Default::default(),
+            bearer_token: None,
+            source: Box::new(Input::FileRef(FileRef::File(PathBuf::from("-")))),
+        });
+        assert_eq!(input, expected);
+
+        let input = Input::from_str(
+            r#"{"source": "https://example.com/api", "bearer_token": {"env": "TOKEN"}}"#,
+        )
+        .unwrap();
+        let Input::Spec(spec) = input else {
+            panic!("not an input spec: {:?}", input)
+        };
+        assert_eq!(
+            spec.bearer_token,
+            Some(crate::http::SecretValue::Env {
+                env: "TOKEN".to_string()
+            })
+        );
+
+        let input = Input::from_str("https://example.com/data.json").unwrap();
+        assert!(matches!(input, Input::FileRef(FileRef::Url(_))));
+
+        let input = Input::from_str("csv:s3://reports/2024/sales%20q1.csv").unwrap();
+        let Input::Spec(spec) = input else {
+            panic!("not an input spec: {:?}", input)
+        };
+        assert_eq!(
+            *spec.source,
+            Input::FileRef(FileRef::S3(crate::s3::S3Location {
+                bucket: "reports".to_string(),
+                key: "2024/sales q1.csv".to_string(),
+            }))
+        );
+        assert!(Input::from_str("s3://reports").is_err());
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        assert_eq!(InputFormat::sniff(b" {\"a\": 1}"), InputFormat::Json);
+        assert_eq!(
+            InputFormat::sniff("\u{feff}\n[{\"a\": 1}]".as_bytes()),
+            InputFormat::Json
+        );
+        assert_eq!(InputFormat::sniff(b"a: 1\nb: [2]\n"), InputFormat::Yaml);
+        assert_eq!(InputFormat::sniff(b"a,b\n1,2\n"), InputFormat::Csv);
+    }
+
+    #[test]
+    fn test_format_from_url() {
+        let format = |url: &str, content_type: Option<&str>| {
+            let url = reqwest::Url::parse(url).unwrap();
+            let content_type = content_type.map(|ct| ct.parse().unwrap());
+            InputFormat::from_url(&url, content_type.as_ref()).ok()
+        };
+        let url = "https://s3.example.com/bucket/data.yml?X-Amz-Signature=abc";
+
+        assert_eq!(
+            format(url, Some("application/vnd.api+json; charset=utf-8")),
+            Some(InputFormat::Json)
+        );
+        assert_eq!(format(url, Some("text/x-yaml")), Some(InputFormat::Yaml));
+        assert_eq!(format(url, Some("text/html")), None);
+        assert_eq!(
+            format(url, Some("binary/octet-stream")),
+            Some(InputFormat::Yaml)
+        );
+        assert_eq!(format(url, None), Some(InputFormat::Yaml));
+        assert_eq!(
+            format("https://example.com/data", None),
+            Some(InputFormat::Json)
+        );
+        assert_eq!(format("https://example.com/data", Some("text/plain")), None);
+    }
+
+    #[test]
+    fn test_deserialize_literal() {
+        let sample = r##"{"literal": "# title", "type": "text/markdown"}"##;
+        let parsed: Input = serde_json::from_str(sample).unwrap();
+        let input = Input::Literal(LiteralInput {
+            literal: "# title".to_string(),
+            mime_type: "text/markdown".to_string(),
+        });
+        assert_eq!(parsed, input);
+
+        // inline data that happens to have the same keys
+        let sample = r#"{"literal": "yes", "type": "letter", "date": "2024-01-01"}"#;
+        let parsed: Input = serde_json::from_str(sample).unwrap();
+        assert!(matches!(parsed, Input::Inline(data) if data.len() == 3));
+    }
+
+    #[test]
+    fn test_merge() {
+        let defaults = || {
+            HashMap::from([(
+                "letter".to_string(),
+                Value::from_serialize(serde_json::json!({
+                    "sender": {"name": "ACME", "city": "Berlin"},
+                    "notes": ["a"],
+                })),
+            )])
+        };
+        let overrides = || {
+            HashMap::from([(
+                "letter".to_string(),
+                Value::from_serialize(serde_json::json!({
+                    "sender": {"city": "Hamburg"},
+                    "notes": ["b"],
+                })),
+            )])
+        };
+        let merged = |strategy: MergeStrategy| {
+            let mut data = defaults();
+            strategy.merge(&mut data, overrides());
+            Value::from_iter(data)
+        };
+
+        assert_eq!(
+            merged(MergeStrategy::Replace),
+            Value::from_serialize(serde_json::json!({"letter": {
+                "sender": {"city": "Hamburg"}, "notes": ["b"]
+            }}))
+        );
+        assert_eq!(
+            merged(MergeStrategy::DeepMerge),
+            Value::from_serialize(serde_json::json!({"letter": {
+                "sender": {"name": "ACME", "city": "Hamburg"}, "notes": ["b"]
+            }}))
+        );
+        assert_eq!(
+            merged(MergeStrategy::AppendArrays),
+            Value::from_serialize(serde_json::json!({"letter": {
+                "sender": {"name": "ACME", "city": "Hamburg"}, "notes": ["a", "b"]
+            }}))
+        );
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let input =
+            "\u{feff}pos;description;amount\n1;\"Consulting; remote\";120.50\n2;Travel;30\n";
+        let name = super::variable_name(None, Path::new("/exports/line-items.csv")).unwrap();
+        let parsed = super::read_csv(name, input.as_bytes()).unwrap();
+        let expected = Value::from_serialize(serde_json::json!([
+            {"pos": "1", "description": "Consulting; remote", "amount": "120.50"},
+            {"pos": "2", "description": "Travel", "amount": "30"},
+        ]));
+        assert_eq!(
+            parsed,
+            HashMap::from([("line_items".to_string(), expected)])
+        );
+    }
 }